@@ -0,0 +1,204 @@
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{Duration, Utc};
+use futures::StreamExt;
+use mongodb::{bson::{doc, oid::ObjectId, DateTime as BsonDateTime}, Client, Collection, Database};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::auth::{generate_secret_token, hash_secret_token, AuthenticatedUser};
+use crate::scope::Scope;
+
+const KEY_PREFIX: &str = "lsk_";
+const MAX_KEY_NAME_LENGTH: usize = 64;
+const MAX_EXPIRY_DAYS: i64 = 365;
+
+/// A personal API key; only the SHA-256 of the full key is stored.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiKey {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    pub name: String,
+    /// Public start of the key, e.g. `lsk_1a2b3c4d`, to tell keys apart.
+    pub prefix: String,
+    pub key_hash: String,
+    #[serde(default)]
+    pub scopes: Vec<Scope>,
+    pub created_at: BsonDateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<BsonDateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<BsonDateTime>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateApiKey {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Never expires when absent.
+    pub expires_in_days: Option<i64>,
+}
+
+fn api_key_json(key: &ApiKey) -> serde_json::Value {
+    json!({
+        "id": key.id.map(|id| id.to_hex()),
+        "name": key.name,
+        "prefix": key.prefix,
+        "scopes": key.scopes,
+        "created_at": key.created_at.try_to_rfc3339_string().ok(),
+        "expires_at": key.expires_at.and_then(|at| at.try_to_rfc3339_string().ok()),
+        "last_used_at": key.last_used_at.and_then(|at| at.try_to_rfc3339_string().ok()),
+    })
+}
+
+/// Returns `lsk_<8 hex>_<secret>` and its `lsk_<8 hex>` prefix.
+fn generate_api_key() -> (String, String) {
+    let mut id: [u8; 4] = [0; 4];
+    rand::thread_rng().fill_bytes(&mut id);
+
+    let prefix: String = format!("{}{}", KEY_PREFIX, id.iter().map(|byte| format!("{:02x}", byte)).collect::<String>());
+    let key: String = format!("{}_{}", prefix, generate_secret_token());
+    (key, prefix)
+}
+
+/// Resolves a presented key to its unexpired record and records the use.
+pub(crate) async fn find_api_key(db: &Database, key: &str) -> mongodb::error::Result<Option<ApiKey>> {
+    if !key.starts_with(KEY_PREFIX) {
+        return Ok(None);
+    }
+
+    let api_keys: Collection<ApiKey> = db.collection("api_keys");
+    api_keys
+        .find_one_and_update(
+            doc! {
+                "key_hash": hash_secret_token(key),
+                "$or": [
+                    { "expires_at": { "$exists": false } },
+                    { "expires_at": { "$gt": BsonDateTime::now() } },
+                ],
+            },
+            doc! { "$set": { "last_used_at": BsonDateTime::now() } },
+        )
+        .await
+}
+
+pub async fn create_api_key(
+    client: web::Data<Client>,
+    authenticated: AuthenticatedUser,
+    request: web::Json<CreateApiKey>,
+) -> impl Responder {
+    let api_keys: Collection<ApiKey> = client.database("shortener_link").collection("api_keys");
+
+    // A leaked key must not be able to mint more keys
+    if authenticated.api_key.is_some() {
+        return HttpResponse::Forbidden().body("API keys cannot manage API keys");
+    }
+
+    let user_id: ObjectId = match authenticated.user.id {
+        Some(id) => id,
+        None => return HttpResponse::InternalServerError().body("User has no ID"),
+    };
+
+    let name: &str = request.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_KEY_NAME_LENGTH {
+        return HttpResponse::BadRequest().body(format!("Name must be between 1 and {} characters", MAX_KEY_NAME_LENGTH));
+    }
+
+    if request.scopes.is_empty() {
+        return HttpResponse::BadRequest().body("At least one scope is required");
+    }
+    if let Some(scope) = request.scopes.iter().find(|scope| !authenticated.has_scope(**scope)) {
+        return HttpResponse::BadRequest().body(format!("Cannot grant scope {}", scope));
+    }
+
+    let expires_at: Option<BsonDateTime> = match request.expires_in_days {
+        Some(days) if !(1..=MAX_EXPIRY_DAYS).contains(&days) => {
+            return HttpResponse::BadRequest().body(format!("expires_in_days must be between 1 and {}", MAX_EXPIRY_DAYS));
+        }
+        Some(days) => Some(BsonDateTime::from_millis((Utc::now() + Duration::days(days)).timestamp_millis())),
+        None => None,
+    };
+
+    let (key, prefix) = generate_api_key();
+    let mut api_key: ApiKey = ApiKey {
+        id: None,
+        user_id,
+        name: name.to_string(),
+        prefix,
+        key_hash: hash_secret_token(&key),
+        scopes: request.scopes.clone(),
+        created_at: BsonDateTime::now(),
+        expires_at,
+        last_used_at: None,
+    };
+
+    match api_keys.insert_one(&api_key).await {
+        Ok(insert_result) => {
+            api_key.id = insert_result.inserted_id.as_object_id();
+
+            // The key itself is only ever shown here
+            let mut body: serde_json::Value = api_key_json(&api_key);
+            body["key"] = json!(key);
+            HttpResponse::Created().json(body)
+        }
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+}
+
+pub async fn list_api_keys(
+    client: web::Data<Client>,
+    authenticated: AuthenticatedUser,
+) -> impl Responder {
+    let api_keys: Collection<ApiKey> = client.database("shortener_link").collection("api_keys");
+
+    let user_id: ObjectId = match authenticated.user.id {
+        Some(id) => id,
+        None => return HttpResponse::InternalServerError().body("User has no ID"),
+    };
+
+    let mut cursor: mongodb::Cursor<ApiKey> = match api_keys.find(doc! { "user_id": user_id }).sort(doc! { "_id": 1 }).await {
+        Ok(cursor) => cursor,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
+
+    let mut keys: Vec<serde_json::Value> = Vec::new();
+
+    while let Some(result) = cursor.next().await {
+        match result {
+            Ok(key) => keys.push(api_key_json(&key)),
+            Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e))
+        }
+    }
+
+    HttpResponse::Ok().json(keys)
+}
+
+pub async fn revoke_api_key(
+    client: web::Data<Client>,
+    authenticated: AuthenticatedUser,
+    path: web::Path<String>,
+) -> impl Responder {
+    let api_keys: Collection<ApiKey> = client.database("shortener_link").collection("api_keys");
+
+    if authenticated.api_key.is_some() {
+        return HttpResponse::Forbidden().body("API keys cannot manage API keys");
+    }
+
+    let user_id: ObjectId = match authenticated.user.id {
+        Some(id) => id,
+        None => return HttpResponse::InternalServerError().body("User has no ID"),
+    };
+
+    let key_id: ObjectId = match ObjectId::parse_str(path.into_inner()) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid API key ID"),
+    };
+
+    // Scoped to the owner so other users' keys look like missing ones
+    match api_keys.delete_one(doc! { "_id": key_id, "user_id": user_id }).await {
+        Ok(delete_result) if delete_result.deleted_count > 0 => HttpResponse::NoContent().finish(),
+        Ok(_) => HttpResponse::NotFound().body("API key not found"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+}
@@ -0,0 +1,386 @@
+use actix_web::{dev::Payload, error::{ErrorInternalServerError, ErrorUnauthorized}, http::header, web, FromRequest, HttpRequest, HttpResponse, Responder};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bcrypt::{hash, DEFAULT_COST};
+use chrono::{Duration, Utc};
+use futures::future::LocalBoxFuture;
+use mongodb::{bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document}, Client, Collection};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::api_key::{find_api_key, ApiKey};
+use crate::jwt::{decode_access_token, decode_email_verification_token, generate_email_verification_token};
+use crate::mail::{app_url, Email, Mailer};
+use crate::scope::{missing_scope, parse_scopes, Scope};
+use crate::user::{validate_password, Role, User};
+
+/// Filter for the user a token's `sub` names. Tokens issued before subjects became
+/// user IDs carry the email instead; that fallback can go once they have all expired.
+pub(crate) fn subject_filter(sub: &str) -> Document {
+    match ObjectId::parse_str(sub) {
+        Ok(id) => doc! { "_id": id },
+        Err(_) => doc! { "email": sub },
+    }
+}
+
+pub(crate) fn is_subject(sub: &str, user: &User) -> bool {
+    match ObjectId::parse_str(sub) {
+        Ok(id) => user.id == Some(id),
+        Err(_) => user.email == sub,
+    }
+}
+
+/// The user behind a request, authenticated by an `Authorization: Bearer` access token
+/// or by a personal API key in `X-API-Key` or `Authorization: ApiKey ...`.
+pub struct AuthenticatedUser {
+    pub user: User,
+    /// The key used, when the request did not come with an access token.
+    pub api_key: Option<ApiKey>,
+    /// What the credential may do, never more than the user's role allows.
+    pub scopes: Vec<Scope>,
+}
+
+impl AuthenticatedUser {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    /// For handlers to declare the scope they need; the error is a ready 403.
+    pub fn require(&self, scope: Scope) -> Result<(), HttpResponse> {
+        if self.has_scope(scope) {
+            Ok(())
+        } else {
+            Err(missing_scope(scope))
+        }
+    }
+}
+
+/// Scopes a credential asked for that the user's current role still allows.
+fn effective_scopes(requested: &[Scope], role: &Role) -> Vec<Scope> {
+    requested.iter().copied().filter(|scope| role.scopes().contains(scope)).collect()
+}
+
+enum Credential {
+    AccessToken(String),
+    ApiKey(String),
+}
+
+fn credential(req: &HttpRequest) -> Option<Credential> {
+    let authorization: Option<&str> = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+
+    if let Some(authorization) = authorization {
+        if let Some(token) = authorization.strip_prefix("Bearer ") {
+            return Some(Credential::AccessToken(token.to_string()));
+        }
+        if let Some(key) = authorization.strip_prefix("ApiKey ") {
+            return Some(Credential::ApiKey(key.to_string()));
+        }
+    }
+
+    req.headers()
+        .get("X-API-Key")
+        .and_then(|value| value.to_str().ok())
+        .map(|key| Credential::ApiKey(key.to_string()))
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let client: Option<web::Data<Client>> = req.app_data::<web::Data<Client>>().cloned();
+        let credential: Option<Credential> = credential(req);
+
+        Box::pin(async move {
+            let credential: Credential = credential.ok_or_else(|| ErrorUnauthorized("Authorization header missing or invalid"))?;
+
+            let client: web::Data<Client> = client.ok_or_else(|| ErrorInternalServerError("Database client is not configured"))?;
+            let db: mongodb::Database = client.database("shortener_link");
+            let collection: Collection<User> = db.collection("users");
+
+            match credential {
+                Credential::AccessToken(token) => {
+                    let claims = decode_access_token(&token).map_err(|_| ErrorUnauthorized("Invalid token"))?;
+
+                    let user: Option<User> = collection
+                        .find_one(subject_filter(&claims.sub))
+                        .await
+                        .map_err(ErrorInternalServerError)?;
+
+                    // Tokens are revoked by removing them from the user document
+                    match user {
+                        Some(user) if user.access_token.as_deref() == Some(token.as_str()) => {
+                            // Tokens from before scopes existed get the role's scopes
+                            let scopes: Vec<Scope> = match &claims.scope {
+                                Some(scope) => effective_scopes(&parse_scopes(scope), &user.role),
+                                None => user.role.scopes().to_vec(),
+                            };
+                            Ok(AuthenticatedUser { user, api_key: None, scopes })
+                        }
+                        _ => Err(ErrorUnauthorized("Invalid token")),
+                    }
+                }
+                Credential::ApiKey(key) => {
+                    let api_key: ApiKey = find_api_key(&db, &key)
+                        .await
+                        .map_err(ErrorInternalServerError)?
+                        .ok_or_else(|| ErrorUnauthorized("Invalid API key"))?;
+
+                    let user: Option<User> = collection
+                        .find_one(doc! { "_id": api_key.user_id })
+                        .await
+                        .map_err(ErrorInternalServerError)?;
+
+                    match user {
+                        Some(user) => {
+                            let scopes: Vec<Scope> = effective_scopes(&api_key.scopes, &user.role);
+                            Ok(AuthenticatedUser { user, api_key: Some(api_key), scopes })
+                        }
+                        None => Err(ErrorUnauthorized("Invalid API key")),
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerifyEmailRequest {
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResendVerificationRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub new_password: String,
+}
+
+/// Pending reset; only the SHA-256 of the emailed token is stored.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasswordReset {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    pub token_hash: String,
+    pub expires_at: BsonDateTime,
+}
+
+/// Random URL-safe token for links sent by email.
+pub(crate) fn generate_secret_token() -> String {
+    let mut bytes: [u8; 32] = [0; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub(crate) fn hash_secret_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+pub(crate) async fn send_verification_email(mailer: &dyn Mailer, user_id: &ObjectId, email: &str) -> Result<(), String> {
+    let token: String = generate_email_verification_token(&user_id.to_hex(), email)
+        .map_err(|e| format!("Error generating verification token: {}", e))?;
+
+    let message: Email = Email {
+        to: email.to_string(),
+        subject: "Verify your email address".to_string(),
+        body: format!(
+            "Confirm your email address by opening the link below:\n\n{}/verify-email?token={}\n\nThe link expires in 24 hours.",
+            app_url(),
+            token
+        ),
+    };
+
+    mailer.send(message).await.map_err(|e| e.to_string())
+}
+
+pub async fn verify_email(
+    client: web::Data<Client>,
+    request: web::Json<VerifyEmailRequest>,
+) -> impl Responder {
+    let db: mongodb::Database = client.database("shortener_link");
+    let collection: Collection<User> = db.collection("users");
+
+    let claims = match decode_email_verification_token(&request.token) {
+        Ok(claims) => claims,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid or expired token"),
+    };
+
+    let object_id: ObjectId = match ObjectId::parse_str(&claims.sub) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid or expired token"),
+    };
+
+    // The email in the token must still be the account's email
+    let update_result = collection
+        .update_one(
+            doc! { "_id": object_id, "email": &claims.email },
+            doc! { "$set": { "verified": true } },
+        )
+        .await;
+
+    match update_result {
+        Ok(update_result) if update_result.matched_count > 0 => HttpResponse::Ok().body("Email verified"),
+        Ok(_) => HttpResponse::BadRequest().body("Invalid or expired token"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+}
+
+pub async fn resend_verification(
+    client: web::Data<Client>,
+    mailer: web::Data<dyn Mailer>,
+    request: web::Json<ResendVerificationRequest>,
+) -> impl Responder {
+    let db: mongodb::Database = client.database("shortener_link");
+    let collection: Collection<User> = db.collection("users");
+
+    match collection.find_one(doc! { "email": &request.email }).await {
+        Ok(Some(User { id: Some(id), email, verified: false, .. })) => {
+            // Sent in the background so the response time doesn't reveal the account
+            let mailer: web::Data<dyn Mailer> = mailer.clone();
+            actix_web::rt::spawn(async move {
+                if let Err(e) = send_verification_email(mailer.get_ref(), &id, &email).await {
+                    eprintln!("{}", e);
+                }
+            });
+        }
+        Ok(_) => {}
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+
+    // Same answer whether or not the account exists
+    HttpResponse::Accepted().body("If the account exists and is unverified, a verification email has been sent")
+}
+
+pub async fn forgot_password(
+    client: web::Data<Client>,
+    mailer: web::Data<dyn Mailer>,
+    request: web::Json<ForgotPasswordRequest>,
+) -> impl Responder {
+    let db: mongodb::Database = client.database("shortener_link");
+    let collection: Collection<User> = db.collection("users");
+    let resets: Collection<PasswordReset> = db.collection("password_resets");
+
+    let user_id: ObjectId = match collection.find_one(doc! { "email": &request.email }).await {
+        Ok(Some(User { id: Some(id), .. })) => id,
+        Ok(_) => return HttpResponse::Accepted().body("If the account exists, a password reset email has been sent"),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
+
+    // Written and sent in the background so known emails answer as fast as unknown ones
+    let email: String = request.email.clone();
+    let mailer: web::Data<dyn Mailer> = mailer.clone();
+    actix_web::rt::spawn(async move {
+        // Only the most recent link stays valid
+        if let Err(e) = resets.delete_many(doc! { "user_id": user_id }).await {
+            eprintln!("{}", e);
+            return;
+        }
+
+        let token: String = generate_secret_token();
+        let reset: PasswordReset = PasswordReset {
+            id: None,
+            user_id,
+            token_hash: hash_secret_token(&token),
+            expires_at: BsonDateTime::from_millis((Utc::now() + Duration::hours(1)).timestamp_millis()),
+        };
+
+        if let Err(e) = resets.insert_one(reset).await {
+            eprintln!("{}", e);
+            return;
+        }
+
+        let message: Email = Email {
+            to: email,
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Someone asked to reset the password of your account. If it was you, open the link below:\n\n{}/reset-password?token={}\n\nThe link expires in 1 hour and can be used once. If you did not ask for this, ignore this email.",
+                app_url(),
+                token
+            ),
+        };
+
+        if let Err(e) = mailer.send(message).await {
+            eprintln!("{}", e);
+        }
+    });
+
+    HttpResponse::Accepted().body("If the account exists, a password reset email has been sent")
+}
+
+pub async fn reset_password(
+    client: web::Data<Client>,
+    request: web::Json<ResetPasswordRequest>,
+) -> impl Responder {
+    let db: mongodb::Database = client.database("shortener_link");
+    let collection: Collection<User> = db.collection("users");
+    let resets: Collection<PasswordReset> = db.collection("password_resets");
+
+    if let Err(message) = validate_password(&request.new_password) {
+        return HttpResponse::BadRequest().body(message);
+    }
+
+    // Deleting on lookup makes the token single-use
+    let reset: PasswordReset = match resets
+        .find_one_and_delete(doc! {
+            "token_hash": hash_secret_token(&request.token),
+            "expires_at": { "$gt": BsonDateTime::now() },
+        })
+        .await
+    {
+        Ok(Some(reset)) => reset,
+        Ok(None) => return HttpResponse::BadRequest().body("Invalid or expired token"),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
+
+    let hashed_password: String = match hash(&request.new_password, DEFAULT_COST) {
+        Ok(hashed_password) => hashed_password,
+        Err(_) => return HttpResponse::InternalServerError().body("Failed to hash the password"),
+    };
+
+    // Revoke every session along with the password
+    let update_result = collection
+        .update_one(
+            doc! { "_id": reset.user_id },
+            doc! {
+                "$set": { "password": hashed_password },
+                "$unset": {
+                    "access_token": "",
+                    "refresh_token": "",
+                    "access_token_expires_at": "",
+                    "refresh_token_expires_at": "",
+                },
+            },
+        )
+        .await;
+
+    match update_result {
+        Ok(update_result) if update_result.matched_count > 0 => HttpResponse::Ok().body("Password has been reset"),
+        Ok(_) => HttpResponse::BadRequest().body("Invalid or expired token"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+}
+
+pub async fn me(AuthenticatedUser { user, scopes, .. }: AuthenticatedUser) -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "id": user.id.map(|id| id.to_hex()),
+        "username": user.username,
+        "email": user.email,
+        "role": user.role,
+        "verified": user.verified,
+        "scopes": scopes,
+    }))
+}
@@ -5,46 +5,229 @@ use std::env;
 //use actix_service::{Service, Transform};
 //use actix_web::dev::ServiceRequest;
 //use futures::{future::{ok, LocalBoxFuture, Ready}, FutureExt};
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
-use jsonwebtoken::{encode, errors::Result as JwtResult, EncodingKey, Header};
-//use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use jsonwebtoken::{encode, errors::{ErrorKind, Result as JwtResult}, EncodingKey, Header};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
 
+use crate::keys::JWT_KEYS;
+use crate::scope::join_scopes;
 use crate::user::Role;
 
+/// Secrets read whenever these tokens are issued or checked.
+const REQUIRED_SECRETS: [&str; 3] = ["REFRESH_SECRET", "MFA_SECRET", "EMAIL_VERIFICATION_SECRET"];
+
+const ACCESS_TOKEN_SECONDS: usize = 900;
+const REFRESH_TOKEN_SECONDS: usize = 604800;
+
+/// What a token may be used for; checked on every decode so one kind cannot stand in for another.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenType {
+    Access,
+    Refresh,
+    Service,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    username: String,
-    sub: String,
-    role: Role,
-    exp: usize,
+pub struct Claims {
+    pub iss: String,
+    pub aud: String,
+    pub sub: String,
+    pub iat: usize,
+    pub nbf: usize,
+    pub exp: usize,
+    pub jti: String,
+    pub typ: TokenType,
+    /// Only set on user tokens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+    /// Space-separated scopes granted to an access token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+}
+
+/// Called from `main` so a missing secret fails at startup rather than halfway through a request.
+pub fn check_secrets() -> Result<(), String> {
+    let missing: Vec<&str> = REQUIRED_SECRETS
+        .iter()
+        .copied()
+        .filter(|name| env::var(name).map_or(true, |value| value.is_empty()))
+        .collect();
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Missing required environment variables: {}", missing.join(", ")))
+    }
+}
+
+fn issuer() -> String {
+    env::var("JWT_ISSUER").unwrap_or_else(|_| "link-shortener".to_string())
+}
+
+fn audience() -> String {
+    env::var("JWT_AUDIENCE").unwrap_or_else(|_| "link-shortener".to_string())
+}
+
+/// Random token ID, so a single token can be told apart and revoked.
+fn generate_jti() -> String {
+    let mut jti: [u8; 16] = [0; 16];
+    rand::thread_rng().fill_bytes(&mut jti);
+    jti.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl Claims {
+    fn new(typ: TokenType, subject: &str, lifetime: usize) -> Claims {
+        let now: usize = chrono::Utc::now().timestamp() as usize;
+
+        Claims {
+            iss: issuer(),
+            aud: audience(),
+            sub: subject.to_string(),
+            iat: now,
+            nbf: now,
+            exp: now + lifetime,
+            jti: generate_jti(),
+            typ,
+            username: None,
+            role: None,
+            scope: None,
+        }
+    }
+}
+
+/// Issuer, audience, `nbf` and `exp` are all enforced, with `JWT_LEEWAY_SECONDS` of clock skew.
+fn validation(algorithm: Algorithm) -> Validation {
+    let leeway: u64 = env::var("JWT_LEEWAY_SECONDS")
+        .ok()
+        .and_then(|leeway| leeway.parse().ok())
+        .unwrap_or(30);
+
+    let mut validation: Validation = Validation::new(algorithm);
+    validation.set_issuer(&[issuer()]);
+    validation.set_audience(&[audience()]);
+    validation.set_required_spec_claims(&["exp", "nbf", "iss", "aud", "sub"]);
+    validation.validate_nbf = true;
+    validation.leeway = leeway;
+    validation
+}
+
+fn expect_type(claims: Claims, typ: TokenType) -> JwtResult<Claims> {
+    if claims.typ == typ {
+        Ok(claims)
+    } else {
+        Err(ErrorKind::InvalidToken.into())
+    }
 }
 
 pub fn generate_jwt(user_id: &str, username: &str, role: &Role) -> JwtResult<String> {
-    let expiration: usize = 10000;
-    let claims: Claims = Claims {
-        username: username.to_string(),
+    let mut claims: Claims = Claims::new(TokenType::Access, user_id, ACCESS_TOKEN_SECONDS);
+    claims.username = Some(username.to_string());
+    claims.role = Some(role.clone());
+    claims.scope = Some(join_scopes(role.scopes()));
+
+    JWT_KEYS.sign(&claims)
+}
+
+pub fn decode_access_token(token: &str) -> JwtResult<Claims> {
+    let claims: Claims = JWT_KEYS.verify(token, validation(JWT_KEYS.algorithm))?;
+    expect_type(claims, TokenType::Access)
+}
+
+/// Refresh tokens are only ever read by this service, so they keep a shared secret.
+pub fn generate_refresh_token(user_id: &str, username: &str, role: &Role) -> JwtResult<String> {
+    let mut claims: Claims = Claims::new(TokenType::Refresh, user_id, REFRESH_TOKEN_SECONDS);
+    claims.username = Some(username.to_string());
+    claims.role = Some(role.clone());
+
+    let refresh_secret_key: String = env::var("REFRESH_SECRET").expect("REFRESH_SECRET must be set");
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(refresh_secret_key.as_ref()))
+}
+
+pub fn decode_refresh_token(token: &str) -> JwtResult<Claims> {
+    let refresh_secret_key: String = env::var("REFRESH_SECRET").expect("REFRESH_SECRET must be set");
+    let claims: Claims = decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(refresh_secret_key.as_ref()),
+        &validation(Algorithm::HS256),
+    )?
+    .claims;
+    expect_type(claims, TokenType::Refresh)
+}
+
+/// Token for another internal service calling this one, with the service name as subject.
+pub fn generate_service_token(service: &str, lifetime_seconds: usize) -> JwtResult<String> {
+    JWT_KEYS.sign(&Claims::new(TokenType::Service, service, lifetime_seconds))
+}
+
+pub fn decode_service_token(token: &str) -> JwtResult<Claims> {
+    let claims: Claims = JWT_KEYS.verify(token, validation(JWT_KEYS.algorithm))?;
+    expect_type(claims, TokenType::Service)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MfaClaims {
+    pub sub: String,
+    pub exp: usize,
+    /// Recorded once the token completes a login, so it cannot be used again.
+    pub jti: String,
+}
+
+/// Short-lived token proving the password step of a login succeeded.
+pub fn generate_mfa_token(user_id: &str) -> JwtResult<String> {
+    let expiration: usize = 300;
+    let claims: MfaClaims = MfaClaims {
         sub: user_id.to_string(),
-        role: role.clone(),
         exp: (chrono::Utc::now().timestamp() as usize) + expiration,
+        jti: generate_jti(),
     };
 
-    let secret_key: String = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+    let secret_key: String = env::var("MFA_SECRET").expect("MFA_SECRET must be set");
     encode(&Header::default(), &claims, &EncodingKey::from_secret(secret_key.as_ref()))
 }
 
-pub fn generate_refresh_token(user_id: &str, username: &str, role: &Role) -> JwtResult<String> {
-    let expiration: usize = 604800;
-    let claims: Claims = Claims {
-        username: username.to_string(),
+pub fn decode_mfa_token(token: &str) -> JwtResult<MfaClaims> {
+    let secret_key: String = env::var("MFA_SECRET").expect("MFA_SECRET must be set");
+    decode::<MfaClaims>(
+        token,
+        &DecodingKey::from_secret(secret_key.as_ref()),
+        &Validation::new(Algorithm::HS256),
+    )
+    .map(|data| data.claims)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmailVerificationClaims {
+    pub sub: String,
+    pub email: String,
+    pub exp: usize,
+}
+
+/// Signs a token proving ownership of `email`; it stops working once the user changes email.
+pub fn generate_email_verification_token(user_id: &str, email: &str) -> JwtResult<String> {
+    let expiration: usize = 86400;
+    let claims: EmailVerificationClaims = EmailVerificationClaims {
         sub: user_id.to_string(),
-        role: role.clone(),
+        email: email.to_string(),
         exp: (chrono::Utc::now().timestamp() as usize) + expiration,
     };
 
-    let refresh_secret_key: String = env::var("REFRESH_SECRET").expect("REFRESH_SECRET must be set");
-    encode(&Header::default(), &claims, &EncodingKey::from_secret(refresh_secret_key.as_ref()))
+    let secret_key: String = env::var("EMAIL_VERIFICATION_SECRET").expect("EMAIL_VERIFICATION_SECRET must be set");
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(secret_key.as_ref()))
 }
 
+pub fn decode_email_verification_token(token: &str) -> JwtResult<EmailVerificationClaims> {
+    let secret_key: String = env::var("EMAIL_VERIFICATION_SECRET").expect("EMAIL_VERIFICATION_SECRET must be set");
+    decode::<EmailVerificationClaims>(
+        token,
+        &DecodingKey::from_secret(secret_key.as_ref()),
+        &Validation::new(Algorithm::HS256),
+    )
+    .map(|data| data.claims)
+}
 
 // pub struct JwtMiddleware;
 
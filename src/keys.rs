@@ -0,0 +1,245 @@
+use std::{env, fs};
+
+use actix_web::{HttpResponse, Responder};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    decode, decode_header, encode,
+    errors::{ErrorKind, Result as JwtResult},
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use lazy_static::lazy_static;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use simple_asn1::{from_der, oid, ASN1Block};
+
+lazy_static! {
+    /// Keys for access tokens, loaded from the environment on first use.
+    pub static ref JWT_KEYS: JwtKeys = JwtKeys::from_env();
+}
+
+/// A key accepted when verifying tokens, selected by the `kid` header.
+pub struct VerificationKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    decoding_key: DecodingKey,
+    /// Public JWK, absent for shared secrets.
+    jwk: Option<Value>,
+}
+
+/// The signing key plus every key tokens may still be verified with during a rotation.
+pub struct JwtKeys {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    encoding_key: EncodingKey,
+    verification_keys: Vec<VerificationKey>,
+}
+
+/// Reads a SubjectPublicKeyInfo PEM into its algorithm and public JWK.
+fn public_jwk(kid: &str, public_pem: &[u8]) -> Result<(Algorithm, Value), String> {
+    let pem = pem::parse(public_pem).map_err(|e| format!("invalid PEM: {}", e))?;
+    if pem.tag() != "PUBLIC KEY" {
+        return Err(format!("expected a PUBLIC KEY PEM, got {}", pem.tag()));
+    }
+
+    let blocks: Vec<ASN1Block> = from_der(pem.contents()).map_err(|e| format!("invalid public key: {}", e))?;
+    let (algorithm, key) = match blocks.as_slice() {
+        [ASN1Block::Sequence(_, items)] => match items.as_slice() {
+            [ASN1Block::Sequence(_, algorithm), ASN1Block::BitString(_, _, key)] => (algorithm, key),
+            _ => return Err("invalid public key".to_string()),
+        },
+        _ => return Err("invalid public key".to_string()),
+    };
+
+    match algorithm.as_slice() {
+        [ASN1Block::ObjectIdentifier(_, id), ..] if *id == oid!(1, 2, 840, 113549, 1, 1, 1) => {
+            let (n, e) = match from_der(key).map_err(|e| format!("invalid RSA key: {}", e))?.as_slice() {
+                [ASN1Block::Sequence(_, parts)] => match parts.as_slice() {
+                    [ASN1Block::Integer(_, n), ASN1Block::Integer(_, e)] => (n.to_bytes_be().1, e.to_bytes_be().1),
+                    _ => return Err("invalid RSA key".to_string()),
+                },
+                _ => return Err("invalid RSA key".to_string()),
+            };
+
+            Ok((Algorithm::RS256, json!({
+                "kty": "RSA",
+                "kid": kid,
+                "use": "sig",
+                "alg": "RS256",
+                "n": URL_SAFE_NO_PAD.encode(n),
+                "e": URL_SAFE_NO_PAD.encode(e),
+            })))
+        }
+        [ASN1Block::ObjectIdentifier(_, id), ASN1Block::ObjectIdentifier(_, curve)]
+            if *id == oid!(1, 2, 840, 10045, 2, 1) && *curve == oid!(1, 2, 840, 10045, 3, 1, 7) =>
+        {
+            // Uncompressed point: 0x04 || x || y
+            if key.len() != 65 || key[0] != 4 {
+                return Err("unsupported P-256 point encoding".to_string());
+            }
+
+            Ok((Algorithm::ES256, json!({
+                "kty": "EC",
+                "kid": kid,
+                "use": "sig",
+                "alg": "ES256",
+                "crv": "P-256",
+                "x": URL_SAFE_NO_PAD.encode(&key[1..33]),
+                "y": URL_SAFE_NO_PAD.encode(&key[33..]),
+            })))
+        }
+        [ASN1Block::ObjectIdentifier(_, id)] if *id == oid!(1, 3, 101, 112) => {
+            Ok((Algorithm::EdDSA, json!({
+                "kty": "OKP",
+                "kid": kid,
+                "use": "sig",
+                "alg": "EdDSA",
+                "crv": "Ed25519",
+                "x": URL_SAFE_NO_PAD.encode(key),
+            })))
+        }
+        _ => Err("unsupported key type, expected RSA, P-256 or Ed25519".to_string()),
+    }
+}
+
+fn decoding_key(algorithm: Algorithm, public_pem: &[u8]) -> Result<DecodingKey, String> {
+    match algorithm {
+        Algorithm::RS256 => DecodingKey::from_rsa_pem(public_pem),
+        Algorithm::ES256 => DecodingKey::from_ec_pem(public_pem),
+        Algorithm::EdDSA => DecodingKey::from_ed_pem(public_pem),
+        other => return Err(format!("unsupported algorithm {:?}", other)),
+    }
+    .map_err(|e| e.to_string())
+}
+
+impl JwtKeys {
+    /// HS256 with a shared secret; nothing is published in the JWKS.
+    pub fn hmac(kid: &str, secret: &[u8]) -> JwtKeys {
+        JwtKeys {
+            kid: kid.to_string(),
+            algorithm: Algorithm::HS256,
+            encoding_key: EncodingKey::from_secret(secret),
+            verification_keys: vec![VerificationKey {
+                kid: kid.to_string(),
+                algorithm: Algorithm::HS256,
+                decoding_key: DecodingKey::from_secret(secret),
+                jwk: None,
+            }],
+        }
+    }
+
+    /// RS256, ES256 or EdDSA from a PKCS#8 private key and its SubjectPublicKeyInfo public key.
+    pub fn from_pem(kid: &str, algorithm: Algorithm, private_pem: &[u8], public_pem: &[u8]) -> Result<JwtKeys, String> {
+        let encoding_key: EncodingKey = match algorithm {
+            Algorithm::RS256 => EncodingKey::from_rsa_pem(private_pem),
+            Algorithm::ES256 => EncodingKey::from_ec_pem(private_pem),
+            Algorithm::EdDSA => EncodingKey::from_ed_pem(private_pem),
+            other => return Err(format!("unsupported algorithm {:?}", other)),
+        }
+        .map_err(|e| e.to_string())?;
+
+        let mut keys: JwtKeys = JwtKeys {
+            kid: kid.to_string(),
+            algorithm,
+            encoding_key,
+            verification_keys: Vec::new(),
+        };
+        keys.add_verification_key(kid, public_pem)?;
+
+        if keys.verification_keys[0].algorithm != algorithm {
+            return Err(format!("public key of {} does not match {:?}", kid, algorithm));
+        }
+
+        Ok(keys)
+    }
+
+    /// Accepts tokens signed by another key, e.g. the previous one during a rotation.
+    pub fn add_verification_key(&mut self, kid: &str, public_pem: &[u8]) -> Result<(), String> {
+        if self.verification_keys.iter().any(|key| key.kid == kid) {
+            return Err(format!("duplicate key id {}", kid));
+        }
+
+        let (algorithm, jwk) = public_jwk(kid, public_pem)?;
+        self.verification_keys.push(VerificationKey {
+            kid: kid.to_string(),
+            algorithm,
+            decoding_key: decoding_key(algorithm, public_pem)?,
+            jwk: Some(jwk),
+        });
+
+        Ok(())
+    }
+
+    /// Reads `JWT_ALGORITHM` (default HS256 with `JWT_SECRET`), `JWT_KEY_ID`,
+    /// `JWT_PRIVATE_KEY_PATH`, `JWT_PUBLIC_KEY_PATH` and `JWT_VERIFICATION_KEYS`
+    /// (comma-separated `kid=path` public keys that are still accepted).
+    pub fn from_env() -> JwtKeys {
+        let kid: String = env::var("JWT_KEY_ID").unwrap_or_else(|_| "default".to_string());
+        let algorithm: String = env::var("JWT_ALGORITHM").unwrap_or_else(|_| "HS256".to_string());
+
+        let mut keys: JwtKeys = match algorithm.as_str() {
+            "HS256" => {
+                let secret_key: String = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+                JwtKeys::hmac(&kid, secret_key.as_bytes())
+            }
+            "RS256" | "ES256" | "EdDSA" => {
+                let private_path: String = env::var("JWT_PRIVATE_KEY_PATH").expect("JWT_PRIVATE_KEY_PATH must be set");
+                let public_path: String = env::var("JWT_PUBLIC_KEY_PATH").expect("JWT_PUBLIC_KEY_PATH must be set");
+                let private_pem: Vec<u8> = fs::read(&private_path).expect("Failed to read JWT_PRIVATE_KEY_PATH");
+                let public_pem: Vec<u8> = fs::read(&public_path).expect("Failed to read JWT_PUBLIC_KEY_PATH");
+                let algorithm: Algorithm = algorithm.parse().expect("JWT_ALGORITHM must be a valid algorithm");
+
+                JwtKeys::from_pem(&kid, algorithm, &private_pem, &public_pem)
+                    .unwrap_or_else(|e| panic!("Invalid JWT signing key: {}", e))
+            }
+            other => panic!("Unsupported JWT_ALGORITHM: {}", other),
+        };
+
+        if let Ok(extra_keys) = env::var("JWT_VERIFICATION_KEYS") {
+            for entry in extra_keys.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+                let (extra_kid, path) = entry
+                    .split_once('=')
+                    .unwrap_or_else(|| panic!("JWT_VERIFICATION_KEYS entry {} must be kid=path", entry));
+                let public_pem: Vec<u8> = fs::read(path).unwrap_or_else(|e| panic!("Failed to read {}: {}", path, e));
+
+                keys.add_verification_key(extra_kid, &public_pem)
+                    .unwrap_or_else(|e| panic!("Invalid JWT verification key {}: {}", extra_kid, e));
+            }
+        }
+
+        keys
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &T) -> JwtResult<String> {
+        let mut header: Header = Header::new(self.algorithm);
+        header.kid = Some(self.kid.clone());
+        encode(&header, claims, &self.encoding_key)
+    }
+
+    /// Verifies with the key named by `kid`; tokens without one predate key ids
+    /// and are checked against the current signing key.
+    pub fn verify<T: DeserializeOwned>(&self, token: &str, mut validation: Validation) -> JwtResult<T> {
+        let header: Header = decode_header(token)?;
+        let kid: &str = header.kid.as_deref().unwrap_or(&self.kid);
+
+        let key: &VerificationKey = self
+            .verification_keys
+            .iter()
+            .find(|key| key.kid == kid)
+            .ok_or(ErrorKind::InvalidToken)?;
+
+        // Never let the token pick its own algorithm
+        validation.algorithms = vec![key.algorithm];
+        decode::<T>(token, &key.decoding_key, &validation).map(|data| data.claims)
+    }
+
+    pub fn jwks(&self) -> Value {
+        let keys: Vec<&Value> = self.verification_keys.iter().filter_map(|key| key.jwk.as_ref()).collect();
+        json!({ "keys": keys })
+    }
+}
+
+pub async fn jwks() -> impl Responder {
+    HttpResponse::Ok()
+        .insert_header(("Cache-Control", "public, max-age=300"))
+        .json(JWT_KEYS.jwks())
+}
@@ -1,4 +1,13 @@
 pub mod routes;
 pub mod user;
 pub mod jwt;
-pub mod proto;
\ No newline at end of file
+pub mod proto;
+pub mod auth;
+pub mod mail;
+pub mod totp;
+pub mod lockout;
+pub mod keys;
+pub mod api_key;
+pub mod scope;
+pub mod org;
+pub mod oidc;
\ No newline at end of file
@@ -0,0 +1,138 @@
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+
+use actix_web::HttpRequest;
+use chrono::{DateTime, Duration, Utc};
+use mongodb::{bson::{doc, DateTime as BsonDateTime}, options::ReturnDocument, Collection, Database};
+use serde::{Deserialize, Serialize};
+
+/// Failures allowed per account before lockouts start.
+const ACCOUNT_FREE_ATTEMPTS: i64 = 5;
+/// Failures allowed per IP before lockouts start; higher because of shared NATs.
+const IP_FREE_ATTEMPTS: i64 = 20;
+const BASE_LOCKOUT_SECONDS: i64 = 30;
+const MAX_LOCKOUT_SECONDS: i64 = 3600;
+/// Failures older than this no longer count.
+const ATTEMPT_WINDOW_SECONDS: i64 = 86400;
+
+/// Failed login attempts for one account or IP, keyed by `account:<email>` or `ip:<address>`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoginAttempts {
+    #[serde(rename = "_id")]
+    pub key: String,
+    pub failures: i64,
+    pub last_failure_at: BsonDateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locked_until: Option<BsonDateTime>,
+}
+
+pub(crate) fn account_key(email: &str) -> String {
+    format!("account:{}", email.trim().to_lowercase())
+}
+
+pub(crate) fn ip_key(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
+
+/// Address the per-IP limit counts against. The TCP peer, unless it is one of the
+/// comma-separated `TRUSTED_PROXIES`; then the client it reports in `Forwarded` or
+/// `X-Forwarded-For`, which that proxy must set rather than append to.
+pub(crate) fn client_ip(req: &HttpRequest) -> Option<String> {
+    let peer_ip: IpAddr = req.peer_addr()?.ip();
+
+    let trusted: bool = env::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|proxy| proxy.trim().parse::<IpAddr>().ok())
+        .any(|proxy| proxy == peer_ip);
+    if !trusted {
+        return Some(peer_ip.to_string());
+    }
+
+    let connection_info = req.connection_info();
+    let forwarded: &str = connection_info.realip_remote_addr()?;
+    // The forwarded value may carry a port
+    let ip: Option<IpAddr> = forwarded
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| forwarded.parse::<SocketAddr>().ok().map(|addr| addr.ip()));
+    Some(ip.unwrap_or(peer_ip).to_string())
+}
+
+fn free_attempts(key: &str) -> i64 {
+    if key.starts_with("ip:") {
+        IP_FREE_ATTEMPTS
+    } else {
+        ACCOUNT_FREE_ATTEMPTS
+    }
+}
+
+fn to_bson(datetime: DateTime<Utc>) -> BsonDateTime {
+    BsonDateTime::from_millis(datetime.timestamp_millis())
+}
+
+/// Latest lockout still running for any of the keys.
+pub(crate) async fn locked_until(db: &Database, keys: &[String]) -> mongodb::error::Result<Option<DateTime<Utc>>> {
+    let attempts: Collection<LoginAttempts> = db.collection("login_attempts");
+
+    let lock: Option<LoginAttempts> = attempts
+        .find_one(doc! {
+            "_id": { "$in": keys },
+            "locked_until": { "$gt": BsonDateTime::now() },
+        })
+        .sort(doc! { "locked_until": -1 })
+        .await?;
+
+    Ok(lock
+        .and_then(|lock| lock.locked_until)
+        .and_then(|locked_until| DateTime::from_timestamp_millis(locked_until.timestamp_millis())))
+}
+
+/// Counts a failure and locks the key for exponentially longer once the free attempts are used up.
+pub(crate) async fn record_failure(db: &Database, key: &str) -> mongodb::error::Result<()> {
+    let attempts: Collection<LoginAttempts> = db.collection("login_attempts");
+    let now: DateTime<Utc> = Utc::now();
+
+    // Start counting again after a quiet period
+    attempts
+        .delete_one(doc! {
+            "_id": key,
+            "last_failure_at": { "$lt": to_bson(now - Duration::seconds(ATTEMPT_WINDOW_SECONDS)) },
+        })
+        .await?;
+
+    let updated: Option<LoginAttempts> = attempts
+        .find_one_and_update(
+            doc! { "_id": key },
+            doc! {
+                "$inc": { "failures": 1_i64 },
+                "$set": { "last_failure_at": to_bson(now) },
+            },
+        )
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .await?;
+
+    let failures: i64 = updated.map(|attempt| attempt.failures).unwrap_or(1);
+    let over_limit: i64 = failures - free_attempts(key);
+
+    if over_limit > 0 {
+        let exponent: u32 = (over_limit - 1).min(16) as u32;
+        let lockout_seconds: i64 = (BASE_LOCKOUT_SECONDS * 2_i64.pow(exponent)).min(MAX_LOCKOUT_SECONDS);
+
+        attempts
+            .update_one(
+                doc! { "_id": key },
+                doc! { "$set": { "locked_until": to_bson(now + Duration::seconds(lockout_seconds)) } },
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn clear_failures(db: &Database, key: &str) -> mongodb::error::Result<()> {
+    let attempts: Collection<LoginAttempts> = db.collection("login_attempts");
+    attempts.delete_one(doc! { "_id": key }).await?;
+    Ok(())
+}
@@ -1,19 +1,23 @@
 use mongodb::{options::ClientOptions, Client};
-use routes::public_routes;
+use api::jwt::check_secrets;
+use api::keys::JWT_KEYS;
+use api::mail::{mailer_from_env, Mailer};
+use api::oidc::OidcProvider;
+use api::routes::public_routes;
+use api::user::{create_user_indexes, User};
 use std::env;
 use dotenv::dotenv;
 
 use actix_web::{web, App, HttpServer};
 
-mod user;
-mod routes;
-mod jwt;
-mod proto;
-
 #[tokio::main]
 async fn main() -> Result<(), Box<mongodb::error::Error>> {
     dotenv().ok();
 
+    // Fail at boot, not on the first request that needs a secret (see .env.example)
+    check_secrets().unwrap_or_else(|e| panic!("{}", e));
+    // Signing keys are loaded lazily; load them now so a bad key also fails at boot
+    lazy_static::initialize(&JWT_KEYS);
 
     let db_uri = env::var("MONGODB_URI").expect("Expected MONGODB_URI in environment variables");
 
@@ -34,10 +38,26 @@ async fn main() -> Result<(), Box<mongodb::error::Error>> {
         }
     };
 
+    // Fails while duplicate accounts from before sign-up checked for them remain
+    let users: mongodb::Collection<User> = client.database("shortener_link").collection("users");
+    if let Err(e) = create_user_indexes(&users).await {
+        eprintln!("Failed to create unique user indexes: {}", e);
+    }
+
+    let mailer: web::Data<dyn Mailer> = web::Data::from(mailer_from_env());
+    // Single sign-on is only offered when a provider is configured
+    let oidc: Option<web::Data<OidcProvider>> = OidcProvider::from_env().map(web::Data::new);
+
     let server = HttpServer::new(move || {
-        App::new()
+        let app = App::new()
             .app_data(web::Data::new(client.clone()))
-            .configure(public_routes)
+            .app_data(mailer.clone());
+
+        match &oidc {
+            Some(oidc) => app.app_data(oidc.clone()),
+            None => app,
+        }
+        .configure(public_routes)
     })
     .bind("0.0.0.0:8080")
     .expect("Failed to bind to 0.0.0.0:8080 - check if the port is already in use or if permissions are insufficient");
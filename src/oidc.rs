@@ -0,0 +1,515 @@
+use std::env;
+
+use actix_web::{cookie::{time::Duration as CookieDuration, Cookie, SameSite}, http::header, web, HttpRequest, HttpResponse, Responder};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bcrypt::{hash, DEFAULT_COST};
+use chrono::{Duration, Utc};
+use jsonwebtoken::{decode, decode_header, jwk::{Jwk, JwkSet}, Algorithm, DecodingKey, Header, Validation};
+use mongodb::{bson::{doc, oid::ObjectId, DateTime as BsonDateTime}, Client, Collection, Database};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+use url::Url;
+
+use crate::auth::{generate_secret_token, hash_secret_token};
+use crate::totp::totp_enabled;
+use crate::user::{issue_tokens, mfa_challenge, Role, User};
+
+/// How long a user has to finish signing in at the provider.
+const LOGIN_MINUTES: i64 = 10;
+/// Holds the login's `state` in the browser that started it.
+const STATE_COOKIE: &str = "oidc_state";
+const STATE_COOKIE_PATH: &str = "/auth/oidc";
+
+/// Provider settings; see `OidcConfig::from_env` for the variables.
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_uri: String,
+    pub scopes: String,
+    /// ID token claim holding the user's groups.
+    pub groups_claim: String,
+    pub admin_groups: Vec<String>,
+    pub auditor_groups: Vec<String>,
+}
+
+fn env_list(name: &str) -> Vec<String> {
+    env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|group| !group.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl OidcConfig {
+    /// Reads `OIDC_ISSUER`, `OIDC_CLIENT_ID`, `OIDC_CLIENT_SECRET`, `OIDC_REDIRECT_URI`,
+    /// `OIDC_SCOPES`, `OIDC_GROUPS_CLAIM` and the comma-separated `OIDC_ADMIN_GROUPS`
+    /// and `OIDC_AUDITOR_GROUPS`; `None` when no issuer is set.
+    pub fn from_env() -> Option<OidcConfig> {
+        let issuer: String = env::var("OIDC_ISSUER").ok()?;
+
+        Some(OidcConfig {
+            issuer,
+            client_id: env::var("OIDC_CLIENT_ID").expect("OIDC_CLIENT_ID must be set"),
+            client_secret: env::var("OIDC_CLIENT_SECRET").ok(),
+            redirect_uri: env::var("OIDC_REDIRECT_URI").expect("OIDC_REDIRECT_URI must be set"),
+            scopes: env::var("OIDC_SCOPES").unwrap_or_else(|_| "openid email profile".to_string()),
+            groups_claim: env::var("OIDC_GROUPS_CLAIM").unwrap_or_else(|_| "groups".to_string()),
+            admin_groups: env_list("OIDC_ADMIN_GROUPS"),
+            auditor_groups: env_list("OIDC_AUDITOR_GROUPS"),
+        })
+    }
+
+    /// Admin wins over auditor; everyone else is a plain user.
+    pub fn role_for_groups(&self, groups: &[String]) -> Role {
+        if groups.iter().any(|group| self.admin_groups.contains(group)) {
+            Role::Admin
+        } else if groups.iter().any(|group| self.auditor_groups.contains(group)) {
+            Role::Auditor
+        } else {
+            Role::User
+        }
+    }
+}
+
+/// The parts of the provider's discovery document used here.
+#[derive(Debug, Clone, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    nonce: Option<String>,
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+    preferred_username: Option<String>,
+    /// Everything else, to find the configured groups claim.
+    #[serde(flatten)]
+    extra: serde_json::Map<String, Value>,
+}
+
+/// A login started at `/auth/oidc/login`, keyed by the hash of its `state`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OidcLogin {
+    #[serde(rename = "_id")]
+    pub state_hash: String,
+    pub nonce: String,
+    pub code_verifier: String,
+    pub expires_at: BsonDateTime,
+}
+
+/// Links a provider account (`issuer` + `subject`) to a local user.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OidcIdentity {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub issuer: String,
+    pub subject: String,
+    pub user_id: ObjectId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OidcCallback {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+/// A configured identity provider; discovery and signing keys are fetched on first use and cached.
+pub struct OidcProvider {
+    config: OidcConfig,
+    http: reqwest::Client,
+    metadata: RwLock<Option<ProviderMetadata>>,
+    jwks: RwLock<Option<JwkSet>>,
+}
+
+/// Tokens without a `kid` are only accepted from providers with a single key.
+fn find_jwk<'a>(jwks: &'a JwkSet, kid: Option<&str>) -> Option<&'a Jwk> {
+    match kid {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    }
+}
+
+impl OidcProvider {
+    pub fn new(config: OidcConfig) -> OidcProvider {
+        OidcProvider {
+            config,
+            http: reqwest::Client::new(),
+            metadata: RwLock::new(None),
+            jwks: RwLock::new(None),
+        }
+    }
+
+    pub fn from_env() -> Option<OidcProvider> {
+        OidcConfig::from_env().map(OidcProvider::new)
+    }
+
+    async fn metadata(&self) -> Result<ProviderMetadata, String> {
+        if let Some(metadata) = self.metadata.read().await.as_ref() {
+            return Ok(metadata.clone());
+        }
+
+        let url: String = format!("{}/.well-known/openid-configuration", self.config.issuer.trim_end_matches('/'));
+        let metadata: ProviderMetadata = self
+            .http
+            .get(&url)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| format!("Failed to fetch {}: {}", url, e))?
+            .json()
+            .await
+            .map_err(|e| format!("Invalid discovery document: {}", e))?;
+
+        if metadata.issuer != self.config.issuer {
+            return Err(format!("Discovery issuer {} does not match {}", metadata.issuer, self.config.issuer));
+        }
+
+        *self.metadata.write().await = Some(metadata.clone());
+        Ok(metadata)
+    }
+
+    /// The provider key named `kid`; the JWKS is only fetched again for a key it does not have yet.
+    async fn jwk(&self, metadata: &ProviderMetadata, kid: Option<&str>) -> Result<Jwk, String> {
+        if let Some(jwk) = self.jwks.read().await.as_ref().and_then(|jwks| find_jwk(jwks, kid)) {
+            return Ok(jwk.clone());
+        }
+
+        // A new kid usually means the provider rotated its keys
+        let jwks: JwkSet = self
+            .http
+            .get(&metadata.jwks_uri)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| format!("Failed to fetch JWKS: {}", e))?
+            .json()
+            .await
+            .map_err(|e| format!("Invalid JWKS: {}", e))?;
+
+        let jwk: Option<Jwk> = find_jwk(&jwks, kid).cloned();
+        *self.jwks.write().await = Some(jwks);
+        jwk.ok_or_else(|| "No matching key for the ID token".to_string())
+    }
+
+    async fn exchange_code(&self, metadata: &ProviderMetadata, code: &str, code_verifier: &str) -> Result<String, String> {
+        let mut form: Vec<(&str, &str)> = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_uri),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(client_secret) = &self.config.client_secret {
+            form.push(("client_secret", client_secret));
+        }
+
+        let response: TokenResponse = self
+            .http
+            .post(&metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| format!("Token request failed: {}", e))?
+            .json()
+            .await
+            .map_err(|e| format!("Invalid token response: {}", e))?;
+
+        Ok(response.id_token)
+    }
+
+    /// Checks the ID token's signature against the provider's JWKS, then issuer, audience, expiry and nonce.
+    async fn verify_id_token(&self, metadata: &ProviderMetadata, id_token: &str, nonce: &str) -> Result<IdTokenClaims, String> {
+        let header: Header = decode_header(id_token).map_err(|e| format!("Invalid ID token: {}", e))?;
+
+        // Shared-secret algorithms would let anyone holding the client secret mint tokens
+        if !matches!(header.alg, Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 | Algorithm::ES256 | Algorithm::ES384 | Algorithm::EdDSA) {
+            return Err(format!("Unsupported ID token algorithm {:?}", header.alg));
+        }
+
+        let jwk: Jwk = self.jwk(metadata, header.kid.as_deref()).await?;
+        let decoding_key: DecodingKey = DecodingKey::from_jwk(&jwk).map_err(|e| format!("Invalid JWK: {}", e))?;
+
+        let mut validation: Validation = Validation::new(header.alg);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        let claims: IdTokenClaims = decode::<IdTokenClaims>(id_token, &decoding_key, &validation)
+            .map_err(|e| format!("Invalid ID token: {}", e))?
+            .claims;
+
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err("ID token nonce does not match".to_string());
+        }
+
+        Ok(claims)
+    }
+
+    fn groups(&self, claims: &IdTokenClaims) -> Vec<String> {
+        match claims.extra.get(&self.config.groups_claim) {
+            Some(Value::Array(groups)) => groups.iter().filter_map(Value::as_str).map(str::to_string).collect(),
+            Some(Value::String(group)) => vec![group.clone()],
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn code_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+/// Username for a new account, made unique with a numeric suffix when taken.
+async fn available_username(collection: &Collection<User>, claims: &IdTokenClaims, email: &str) -> mongodb::error::Result<String> {
+    let base: String = claims
+        .preferred_username
+        .clone()
+        .unwrap_or_else(|| email.split('@').next().unwrap_or(email).to_string());
+
+    let mut username: String = base.clone();
+    let mut suffix: u32 = 1;
+    while collection.count_documents(doc! { "username": &username }).await? > 0 {
+        suffix += 1;
+        username = format!("{}{}", base, suffix);
+    }
+    Ok(username)
+}
+
+/// Finds the user linked to the provider account, links a verified account with the
+/// provider's verified email, or creates one.
+async fn resolve_user(db: &Database, issuer: &str, claims: &IdTokenClaims, role: Role) -> Result<User, HttpResponse> {
+    let collection: Collection<User> = db.collection("users");
+    let identities: Collection<OidcIdentity> = db.collection("oidc_identities");
+    let server_error = |e: mongodb::error::Error| HttpResponse::InternalServerError().body(format!("Error: {}", e));
+
+    let role_bson = mongodb::bson::to_bson(&role).map_err(|e| HttpResponse::InternalServerError().body(format!("Error: {}", e)))?;
+
+    // The provider decides the role on every login
+    if let Some(identity) = identities.find_one(doc! { "issuer": issuer, "subject": &claims.sub }).await.map_err(server_error)? {
+        return collection
+            .find_one_and_update(doc! { "_id": identity.user_id }, doc! { "$set": { "role": &role_bson } })
+            .return_document(mongodb::options::ReturnDocument::After)
+            .await
+            .map_err(server_error)?
+            .ok_or_else(|| HttpResponse::Unauthorized().body("Linked account no longer exists"));
+    }
+
+    // Matching by email is only safe when the provider vouches for it
+    let email: &str = match (&claims.email, claims.email_verified) {
+        (Some(email), true) => email,
+        _ => return Err(HttpResponse::Forbidden().body("Email not verified")),
+    };
+
+    // Only verified accounts are linked: anyone can register an unverified one with
+    // this address and a password of their choosing
+    let user: User = match collection
+        .find_one_and_update(doc! { "email": email, "verified": true }, doc! { "$set": { "role": &role_bson } })
+        .return_document(mongodb::options::ReturnDocument::After)
+        .await
+        .map_err(server_error)?
+    {
+        Some(user) => user,
+        None if collection.count_documents(doc! { "email": email }).await.map_err(server_error)? > 0 => {
+            return Err(HttpResponse::Conflict().body("An unverified account uses this email; verify it before signing in with single sign-on"));
+        }
+        None => {
+            // Nobody knows this password; the account signs in through the provider
+            let password: String = hash(generate_secret_token(), DEFAULT_COST)
+                .map_err(|_| HttpResponse::InternalServerError().body("Failed to hash the password"))?;
+
+            let mut user: User = User {
+                id: None,
+                username: available_username(&collection, claims, email).await.map_err(server_error)?,
+                email: email.to_string(),
+                password,
+                role,
+                access_token: None,
+                refresh_token: None,
+                access_token_expires_at: None,
+                refresh_token_expires_at: None,
+                verified: true,
+            };
+
+            let insert_result = collection.insert_one(&user).await.map_err(server_error)?;
+            user.id = insert_result.inserted_id.as_object_id();
+            user
+        }
+    };
+
+    let user_id: ObjectId = user.id.ok_or_else(|| HttpResponse::InternalServerError().body("User has no ID"))?;
+    let identity: OidcIdentity = OidcIdentity {
+        id: None,
+        issuer: issuer.to_string(),
+        subject: claims.sub.clone(),
+        user_id,
+    };
+    identities.insert_one(identity).await.map_err(server_error)?;
+
+    Ok(user)
+}
+
+/// Starts an authorization-code login with PKCE by redirecting to the provider.
+pub async fn oidc_login(
+    client: web::Data<Client>,
+    provider: Option<web::Data<OidcProvider>>,
+) -> impl Responder {
+    let db: mongodb::Database = client.database("shortener_link");
+    let logins: Collection<OidcLogin> = db.collection("oidc_logins");
+
+    let provider: web::Data<OidcProvider> = match provider {
+        Some(provider) => provider,
+        None => return HttpResponse::NotFound().body("Single sign-on is not configured"),
+    };
+
+    let metadata: ProviderMetadata = match provider.metadata().await {
+        Ok(metadata) => metadata,
+        Err(e) => return HttpResponse::BadGateway().body(e),
+    };
+
+    let state: String = generate_secret_token();
+    let nonce: String = generate_secret_token();
+    let code_verifier: String = generate_secret_token();
+
+    let login: OidcLogin = OidcLogin {
+        state_hash: hash_secret_token(&state),
+        nonce: nonce.clone(),
+        code_verifier: code_verifier.clone(),
+        expires_at: BsonDateTime::from_millis((Utc::now() + Duration::minutes(LOGIN_MINUTES)).timestamp_millis()),
+    };
+
+    if let Err(e) = logins.insert_one(login).await {
+        return HttpResponse::InternalServerError().body(format!("Error: {}", e));
+    }
+
+    let mut url: Url = match Url::parse(&metadata.authorization_endpoint) {
+        Ok(url) => url,
+        Err(e) => return HttpResponse::BadGateway().body(format!("Invalid authorization endpoint: {}", e)),
+    };
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &provider.config.client_id)
+        .append_pair("redirect_uri", &provider.config.redirect_uri)
+        .append_pair("scope", &provider.config.scopes)
+        .append_pair("state", &state)
+        .append_pair("nonce", &nonce)
+        .append_pair("code_challenge", &code_challenge(&code_verifier))
+        .append_pair("code_challenge_method", "S256");
+
+    // Only this browser can finish the login, so a victim cannot be sent a callback for the attacker's account
+    let state_cookie: Cookie = Cookie::build(STATE_COOKIE, state)
+        .path(STATE_COOKIE_PATH)
+        .http_only(true)
+        .secure(provider.config.redirect_uri.starts_with("https://"))
+        .same_site(SameSite::Lax)
+        .max_age(CookieDuration::minutes(LOGIN_MINUTES))
+        .finish();
+
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, url.to_string()))
+        .cookie(state_cookie)
+        .finish()
+}
+
+/// Finishes the login: exchanges the code, verifies the ID token and issues this service's
+/// tokens, or asks for the TOTP code like `login_user` when the account has one enrolled.
+pub async fn oidc_callback(
+    req: HttpRequest,
+    client: web::Data<Client>,
+    provider: Option<web::Data<OidcProvider>>,
+    query: web::Query<OidcCallback>,
+) -> impl Responder {
+    let db: mongodb::Database = client.database("shortener_link");
+    let collection: Collection<User> = db.collection("users");
+    let logins: Collection<OidcLogin> = db.collection("oidc_logins");
+
+    let provider: web::Data<OidcProvider> = match provider {
+        Some(provider) => provider,
+        None => return HttpResponse::NotFound().body("Single sign-on is not configured"),
+    };
+
+    if let Some(error) = &query.error {
+        return HttpResponse::Unauthorized().body(format!("Sign-in was not completed: {}", error));
+    }
+
+    let (code, state) = match (&query.code, &query.state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => return HttpResponse::BadRequest().body("Missing code or state"),
+    };
+
+    let started_here: bool = req
+        .cookie(STATE_COOKIE)
+        .is_some_and(|cookie| hash_secret_token(cookie.value()) == hash_secret_token(state));
+    if !started_here {
+        return HttpResponse::BadRequest().body("Sign-in was not started in this browser");
+    }
+
+    // Deleting on lookup makes every state single-use
+    let login: OidcLogin = match logins
+        .find_one_and_delete(doc! {
+            "_id": hash_secret_token(state),
+            "expires_at": { "$gt": BsonDateTime::now() },
+        })
+        .await
+    {
+        Ok(Some(login)) => login,
+        Ok(None) => return HttpResponse::BadRequest().body("Invalid or expired state"),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
+
+    let metadata: ProviderMetadata = match provider.metadata().await {
+        Ok(metadata) => metadata,
+        Err(e) => return HttpResponse::BadGateway().body(e),
+    };
+
+    let id_token: String = match provider.exchange_code(&metadata, code, &login.code_verifier).await {
+        Ok(id_token) => id_token,
+        Err(e) => return HttpResponse::BadGateway().body(e),
+    };
+
+    let claims: IdTokenClaims = match provider.verify_id_token(&metadata, &id_token, &login.nonce).await {
+        Ok(claims) => claims,
+        Err(e) => return HttpResponse::Unauthorized().body(e),
+    };
+
+    let role: Role = provider.config.role_for_groups(&provider.groups(&claims));
+
+    let user: User = match resolve_user(&db, &metadata.issuer, &claims, role).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    // The provider stands in for the password, not for a second factor enrolled here
+    let user_id: ObjectId = match user.id {
+        Some(id) => id,
+        None => return HttpResponse::InternalServerError().body("User has no ID"),
+    };
+    let mut response: HttpResponse = match totp_enabled(&db, &user_id).await {
+        Ok(true) => mfa_challenge(&user_id),
+        Ok(false) => issue_tokens(&collection, &user).await,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
+
+    if let Err(e) = response.add_removal_cookie(&Cookie::build(STATE_COOKIE, "").path(STATE_COOKIE_PATH).finish()) {
+        eprintln!("{}", e);
+    }
+    response
+}
@@ -0,0 +1,681 @@
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{Duration, Utc};
+use futures::StreamExt;
+use mongodb::{bson::{doc, oid::ObjectId, DateTime as BsonDateTime}, Client, Collection, Database};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::auth::{generate_secret_token, hash_secret_token, AuthenticatedUser};
+use crate::mail::{app_url, Email, Mailer};
+use crate::scope::Scope;
+use crate::user::User;
+
+const MAX_ORG_NAME_LENGTH: usize = 64;
+const INVITATION_DAYS: i64 = 7;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OrgRole {
+    Owner,
+    Admin,
+    Editor,
+    Viewer,
+}
+
+impl OrgRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrgRole::Owner => "owner",
+            OrgRole::Admin => "admin",
+            OrgRole::Editor => "editor",
+            OrgRole::Viewer => "viewer",
+        }
+    }
+
+    /// Owners and admins manage members and invitations.
+    pub fn can_manage_members(&self) -> bool {
+        matches!(self, OrgRole::Owner | OrgRole::Admin)
+    }
+
+    /// Owners manage everyone; admins only the editors and viewers below them.
+    pub fn can_manage(&self, other: OrgRole) -> bool {
+        match self {
+            OrgRole::Owner => true,
+            OrgRole::Admin => matches!(other, OrgRole::Editor | OrgRole::Viewer),
+            OrgRole::Editor | OrgRole::Viewer => false,
+        }
+    }
+
+    /// What a member may do with the organization's links.
+    pub fn scopes(&self) -> &'static [Scope] {
+        match self {
+            OrgRole::Owner | OrgRole::Admin => &[Scope::LinksRead, Scope::LinksWrite, Scope::LinksDelete, Scope::AnalyticsRead],
+            OrgRole::Editor => &[Scope::LinksRead, Scope::LinksWrite, Scope::AnalyticsRead],
+            OrgRole::Viewer => &[Scope::LinksRead, Scope::AnalyticsRead],
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Organization {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub name: String,
+    pub created_at: BsonDateTime,
+    /// User IDs of the owners, mirrored from the memberships so the last one can be
+    /// protected with a single conditional update.
+    #[serde(default)]
+    pub owners: Vec<ObjectId>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Membership {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub org_id: ObjectId,
+    pub user_id: ObjectId,
+    pub role: OrgRole,
+    pub joined_at: BsonDateTime,
+}
+
+/// Pending invitation; only the SHA-256 of the emailed token is stored.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Invitation {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub org_id: ObjectId,
+    pub email: String,
+    pub role: OrgRole,
+    pub token_hash: String,
+    pub invited_by: ObjectId,
+    pub expires_at: BsonDateTime,
+}
+
+/// The organization a user currently works in, keyed by user ID.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ActiveOrg {
+    #[serde(rename = "_id")]
+    pub user_id: ObjectId,
+    pub org_id: ObjectId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateOrganization {
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InviteMember {
+    pub email: String,
+    pub role: OrgRole,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AcceptInvitation {
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateMember {
+    pub role: OrgRole,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetActiveOrg {
+    pub org_id: String,
+}
+
+pub(crate) async fn find_membership(db: &Database, org_id: ObjectId, user_id: ObjectId) -> mongodb::error::Result<Option<Membership>> {
+    let memberships: Collection<Membership> = db.collection("org_members");
+    memberships.find_one(doc! { "org_id": org_id, "user_id": user_id }).await
+}
+
+/// A membership together with what the caller may do in the organization.
+#[derive(Debug)]
+pub struct ActiveMembership {
+    pub membership: Membership,
+    pub scopes: Vec<Scope>,
+}
+
+/// The member role's scopes, narrowed to those of the credential in use.
+fn org_scopes(role: OrgRole, authenticated: &AuthenticatedUser) -> Vec<Scope> {
+    role.scopes().iter().copied().filter(|scope| authenticated.has_scope(*scope)).collect()
+}
+
+/// Membership in the caller's active organization, if they still belong to it.
+/// Link and analytics queries filter by its `org_id` and check its `scopes`.
+pub async fn active_membership(db: &Database, authenticated: &AuthenticatedUser) -> mongodb::error::Result<Option<ActiveMembership>> {
+    let active_orgs: Collection<ActiveOrg> = db.collection("active_orgs");
+
+    let user_id: ObjectId = match authenticated.user.id {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+
+    let membership: Option<Membership> = match active_orgs.find_one(doc! { "_id": user_id }).await? {
+        Some(active) => find_membership(db, active.org_id, user_id).await?,
+        None => None,
+    };
+
+    Ok(membership.map(|membership| ActiveMembership {
+        scopes: org_scopes(membership.role, authenticated),
+        membership,
+    }))
+}
+
+async fn set_active_org(db: &Database, user_id: ObjectId, org_id: ObjectId) -> mongodb::error::Result<()> {
+    let active_orgs: Collection<ActiveOrg> = db.collection("active_orgs");
+    active_orgs
+        .update_one(doc! { "_id": user_id }, doc! { "$set": { "org_id": org_id } })
+        .upsert(true)
+        .await?;
+    Ok(())
+}
+
+async fn add_owner(db: &Database, org_id: ObjectId, user_id: ObjectId) -> mongodb::error::Result<()> {
+    let organizations: Collection<Organization> = db.collection("organizations");
+    organizations
+        .update_one(doc! { "_id": org_id }, doc! { "$addToSet": { "owners": user_id } })
+        .await?;
+    Ok(())
+}
+
+/// Takes `user_id` off the owners unless they are the last one; `false` when refused.
+async fn release_owner(db: &Database, org_id: ObjectId, user_id: ObjectId) -> mongodb::error::Result<bool> {
+    let organizations: Collection<Organization> = db.collection("organizations");
+    let update_result = organizations
+        .update_one(
+            // A second entry has to exist, checked and changed in one step
+            doc! { "_id": org_id, "owners": user_id, "owners.1": { "$exists": true } },
+            doc! { "$pull": { "owners": user_id } },
+        )
+        .await?;
+    Ok(update_result.modified_count > 0)
+}
+
+fn parse_ids(org_id: &str, user_id: Option<&str>) -> Result<(ObjectId, Option<ObjectId>), HttpResponse> {
+    let org_id: ObjectId = ObjectId::parse_str(org_id)
+        .map_err(|_| HttpResponse::BadRequest().body("Invalid organization ID"))?;
+    let user_id: Option<ObjectId> = user_id
+        .map(ObjectId::parse_str)
+        .transpose()
+        .map_err(|_| HttpResponse::BadRequest().body("Invalid user ID"))?;
+    Ok((org_id, user_id))
+}
+
+/// The caller's membership in `org_id`; non-members get the same 404 as a missing organization.
+async fn require_membership(db: &Database, org_id: ObjectId, user: &User) -> Result<Membership, HttpResponse> {
+    let user_id: ObjectId = user.id.ok_or_else(|| HttpResponse::InternalServerError().body("User has no ID"))?;
+
+    match find_membership(db, org_id, user_id).await {
+        Ok(Some(membership)) => Ok(membership),
+        Ok(None) => Err(HttpResponse::NotFound().body("Organization not found")),
+        Err(e) => Err(HttpResponse::InternalServerError().body(format!("Error: {}", e))),
+    }
+}
+
+pub async fn create_org(
+    client: web::Data<Client>,
+    authenticated: AuthenticatedUser,
+    request: web::Json<CreateOrganization>,
+) -> impl Responder {
+    let db: mongodb::Database = client.database("shortener_link");
+    let organizations: Collection<Organization> = db.collection("organizations");
+    let memberships: Collection<Membership> = db.collection("org_members");
+
+    // Membership changes need a signed-in session, like API key management
+    if authenticated.api_key.is_some() {
+        return HttpResponse::Forbidden().body("API keys cannot manage organizations");
+    }
+
+    let user_id: ObjectId = match authenticated.user.id {
+        Some(id) => id,
+        None => return HttpResponse::InternalServerError().body("User has no ID"),
+    };
+
+    let name: &str = request.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_ORG_NAME_LENGTH {
+        return HttpResponse::BadRequest().body(format!("Name must be between 1 and {} characters", MAX_ORG_NAME_LENGTH));
+    }
+
+    let organization: Organization = Organization {
+        id: None,
+        name: name.to_string(),
+        created_at: BsonDateTime::now(),
+        owners: vec![user_id],
+    };
+
+    let org_id: ObjectId = match organizations.insert_one(&organization).await {
+        Ok(insert_result) => match insert_result.inserted_id.as_object_id() {
+            Some(id) => id,
+            None => return HttpResponse::InternalServerError().body("Organization has no ID"),
+        },
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
+
+    let membership: Membership = Membership {
+        id: None,
+        org_id,
+        user_id,
+        role: OrgRole::Owner,
+        joined_at: BsonDateTime::now(),
+    };
+
+    if let Err(e) = memberships.insert_one(membership).await {
+        return HttpResponse::InternalServerError().body(format!("Error: {}", e));
+    }
+
+    // A new organization is where the creator wants to work next
+    if let Err(e) = set_active_org(&db, user_id, org_id).await {
+        return HttpResponse::InternalServerError().body(format!("Error: {}", e));
+    }
+
+    HttpResponse::Created().json(json!({
+        "id": org_id.to_hex(),
+        "name": organization.name,
+        "role": OrgRole::Owner,
+    }))
+}
+
+pub async fn list_orgs(
+    client: web::Data<Client>,
+    authenticated: AuthenticatedUser,
+) -> impl Responder {
+    let db: mongodb::Database = client.database("shortener_link");
+    let organizations: Collection<Organization> = db.collection("organizations");
+    let memberships: Collection<Membership> = db.collection("org_members");
+
+    let user_id: ObjectId = match authenticated.user.id {
+        Some(id) => id,
+        None => return HttpResponse::InternalServerError().body("User has no ID"),
+    };
+
+    let active_org_id: Option<ObjectId> = match active_membership(&db, &authenticated).await {
+        Ok(active) => active.map(|active| active.membership.org_id),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
+
+    let mut cursor: mongodb::Cursor<Membership> = match memberships.find(doc! { "user_id": user_id }).sort(doc! { "org_id": 1 }).await {
+        Ok(cursor) => cursor,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
+
+    let mut orgs: Vec<serde_json::Value> = Vec::new();
+
+    while let Some(result) = cursor.next().await {
+        let membership: Membership = match result {
+            Ok(membership) => membership,
+            Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e))
+        };
+
+        match organizations.find_one(doc! { "_id": membership.org_id }).await {
+            Ok(Some(organization)) => orgs.push(json!({
+                "id": membership.org_id.to_hex(),
+                "name": organization.name,
+                "role": membership.role,
+                "active": active_org_id == Some(membership.org_id),
+            })),
+            Ok(None) => {}
+            Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+        }
+    }
+
+    HttpResponse::Ok().json(orgs)
+}
+
+pub async fn set_active(
+    client: web::Data<Client>,
+    authenticated: AuthenticatedUser,
+    request: web::Json<SetActiveOrg>,
+) -> impl Responder {
+    let db: mongodb::Database = client.database("shortener_link");
+
+    // The active organization applies to every session, so keys may not move it
+    if authenticated.api_key.is_some() {
+        return HttpResponse::Forbidden().body("API keys cannot manage organizations");
+    }
+
+    let org_id: ObjectId = match parse_ids(&request.org_id, None) {
+        Ok((org_id, _)) => org_id,
+        Err(response) => return response,
+    };
+
+    let membership: Membership = match require_membership(&db, org_id, &authenticated.user).await {
+        Ok(membership) => membership,
+        Err(response) => return response,
+    };
+
+    match set_active_org(&db, membership.user_id, org_id).await {
+        Ok(()) => HttpResponse::Ok().json(json!({
+            "org_id": org_id.to_hex(),
+            "role": membership.role,
+            "scopes": org_scopes(membership.role, &authenticated),
+        })),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+}
+
+pub async fn list_members(
+    client: web::Data<Client>,
+    authenticated: AuthenticatedUser,
+    path: web::Path<String>,
+) -> impl Responder {
+    let db: mongodb::Database = client.database("shortener_link");
+    let memberships: Collection<Membership> = db.collection("org_members");
+    let users: Collection<User> = db.collection("users");
+
+    let org_id: ObjectId = match parse_ids(&path.into_inner(), None) {
+        Ok((org_id, _)) => org_id,
+        Err(response) => return response,
+    };
+
+    if let Err(response) = require_membership(&db, org_id, &authenticated.user).await {
+        return response;
+    }
+
+    let mut cursor: mongodb::Cursor<Membership> = match memberships.find(doc! { "org_id": org_id }).sort(doc! { "_id": 1 }).await {
+        Ok(cursor) => cursor,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
+
+    let mut members: Vec<serde_json::Value> = Vec::new();
+
+    while let Some(result) = cursor.next().await {
+        let membership: Membership = match result {
+            Ok(membership) => membership,
+            Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e))
+        };
+
+        match users.find_one(doc! { "_id": membership.user_id }).await {
+            Ok(Some(user)) => members.push(json!({
+                "user_id": membership.user_id.to_hex(),
+                "username": user.username,
+                "role": membership.role,
+            })),
+            Ok(None) => {}
+            Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+        }
+    }
+
+    HttpResponse::Ok().json(members)
+}
+
+pub async fn update_member(
+    client: web::Data<Client>,
+    authenticated: AuthenticatedUser,
+    path: web::Path<(String, String)>,
+    request: web::Json<UpdateMember>,
+) -> impl Responder {
+    let db: mongodb::Database = client.database("shortener_link");
+    let memberships: Collection<Membership> = db.collection("org_members");
+
+    if authenticated.api_key.is_some() {
+        return HttpResponse::Forbidden().body("API keys cannot manage organizations");
+    }
+
+    let (org_id, user_id): (String, String) = path.into_inner();
+    let (org_id, member_id) = match parse_ids(&org_id, Some(&user_id)) {
+        Ok((org_id, Some(member_id))) => (org_id, member_id),
+        Ok(_) => return HttpResponse::BadRequest().body("Invalid user ID"),
+        Err(response) => return response,
+    };
+
+    let caller: Membership = match require_membership(&db, org_id, &authenticated.user).await {
+        Ok(membership) => membership,
+        Err(response) => return response,
+    };
+
+    let member: Membership = match find_membership(&db, org_id, member_id).await {
+        Ok(Some(member)) => member,
+        Ok(None) => return HttpResponse::NotFound().body("Member not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
+
+    if !caller.role.can_manage_members() {
+        return HttpResponse::Forbidden().body("Only owners and admins can change roles");
+    }
+    // Admins manage everyone below them; ownership only changes hands between owners
+    if (member.role == OrgRole::Owner || request.role == OrgRole::Owner) && caller.role != OrgRole::Owner {
+        return HttpResponse::Forbidden().body("Only owners can grant or revoke ownership");
+    }
+    if caller.user_id != member_id && !caller.role.can_manage(member.role) {
+        return HttpResponse::Forbidden().body("Only owners can change other admins");
+    }
+
+    if member.role == OrgRole::Owner && request.role != OrgRole::Owner {
+        match release_owner(&db, org_id, member_id).await {
+            Ok(true) => {}
+            Ok(false) => return HttpResponse::Conflict().body("An organization needs at least one owner"),
+            Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+        }
+    }
+    if request.role == OrgRole::Owner {
+        if let Err(e) = add_owner(&db, org_id, member_id).await {
+            return HttpResponse::InternalServerError().body(format!("Error: {}", e));
+        }
+    }
+
+    match memberships.update_one(doc! { "org_id": org_id, "user_id": member_id }, doc! { "$set": { "role": request.role.as_str() } }).await {
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "user_id": member_id.to_hex(),
+            "role": request.role,
+        })),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+}
+
+pub async fn remove_member(
+    client: web::Data<Client>,
+    authenticated: AuthenticatedUser,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let db: mongodb::Database = client.database("shortener_link");
+    let memberships: Collection<Membership> = db.collection("org_members");
+    let active_orgs: Collection<ActiveOrg> = db.collection("active_orgs");
+
+    if authenticated.api_key.is_some() {
+        return HttpResponse::Forbidden().body("API keys cannot manage organizations");
+    }
+
+    let (org_id, user_id): (String, String) = path.into_inner();
+    let (org_id, member_id) = match parse_ids(&org_id, Some(&user_id)) {
+        Ok((org_id, Some(member_id))) => (org_id, member_id),
+        Ok(_) => return HttpResponse::BadRequest().body("Invalid user ID"),
+        Err(response) => return response,
+    };
+
+    let caller: Membership = match require_membership(&db, org_id, &authenticated.user).await {
+        Ok(membership) => membership,
+        Err(response) => return response,
+    };
+
+    let member: Membership = match find_membership(&db, org_id, member_id).await {
+        Ok(Some(member)) => member,
+        Ok(None) => return HttpResponse::NotFound().body("Member not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
+
+    // Members may always leave; removing others follows the same rules as changing roles
+    if caller.user_id != member_id {
+        if !caller.role.can_manage_members() {
+            return HttpResponse::Forbidden().body("Only owners and admins can remove members");
+        }
+        if member.role == OrgRole::Owner && caller.role != OrgRole::Owner {
+            return HttpResponse::Forbidden().body("Only owners can remove owners");
+        }
+        if !caller.role.can_manage(member.role) {
+            return HttpResponse::Forbidden().body("Only owners can remove other admins");
+        }
+    }
+
+    if member.role == OrgRole::Owner {
+        match release_owner(&db, org_id, member_id).await {
+            Ok(true) => {}
+            Ok(false) => return HttpResponse::Conflict().body("An organization needs at least one owner"),
+            Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+        }
+    }
+
+    if let Err(e) = memberships.delete_one(doc! { "org_id": org_id, "user_id": member_id }).await {
+        return HttpResponse::InternalServerError().body(format!("Error: {}", e));
+    }
+
+    match active_orgs.delete_one(doc! { "_id": member_id, "org_id": org_id }).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+}
+
+pub async fn invite_member(
+    client: web::Data<Client>,
+    mailer: web::Data<dyn Mailer>,
+    authenticated: AuthenticatedUser,
+    path: web::Path<String>,
+    request: web::Json<InviteMember>,
+) -> impl Responder {
+    let db: mongodb::Database = client.database("shortener_link");
+    let organizations: Collection<Organization> = db.collection("organizations");
+    let invitations: Collection<Invitation> = db.collection("org_invitations");
+
+    if authenticated.api_key.is_some() {
+        return HttpResponse::Forbidden().body("API keys cannot manage organizations");
+    }
+
+    let org_id: ObjectId = match parse_ids(&path.into_inner(), None) {
+        Ok((org_id, _)) => org_id,
+        Err(response) => return response,
+    };
+
+    let caller: Membership = match require_membership(&db, org_id, &authenticated.user).await {
+        Ok(membership) => membership,
+        Err(response) => return response,
+    };
+
+    if !caller.role.can_manage_members() {
+        return HttpResponse::Forbidden().body("Only owners and admins can invite members");
+    }
+    if request.role == OrgRole::Owner && caller.role != OrgRole::Owner {
+        return HttpResponse::Forbidden().body("Only owners can grant or revoke ownership");
+    }
+
+    let organization: Organization = match organizations.find_one(doc! { "_id": org_id }).await {
+        Ok(Some(organization)) => organization,
+        Ok(None) => return HttpResponse::NotFound().body("Organization not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
+
+    let email: String = request.email.trim().to_lowercase();
+
+    // Inviting the same address again replaces the earlier link
+    if let Err(e) = invitations.delete_many(doc! { "org_id": org_id, "email": &email }).await {
+        return HttpResponse::InternalServerError().body(format!("Error: {}", e));
+    }
+
+    let token: String = generate_secret_token();
+    let invitation: Invitation = Invitation {
+        id: None,
+        org_id,
+        email: email.clone(),
+        role: request.role,
+        token_hash: hash_secret_token(&token),
+        invited_by: caller.user_id,
+        expires_at: BsonDateTime::from_millis((Utc::now() + Duration::days(INVITATION_DAYS)).timestamp_millis()),
+    };
+
+    if let Err(e) = invitations.insert_one(invitation).await {
+        return HttpResponse::InternalServerError().body(format!("Error: {}", e));
+    }
+
+    let message: Email = Email {
+        to: email.clone(),
+        subject: format!("You have been invited to {}", organization.name),
+        body: format!(
+            "{} invited you to join {} as {}. Sign in with this email address and open the link below to accept:\n\n{}/accept-invitation?token={}\n\nThe link expires in {} days.",
+            authenticated.user.username,
+            organization.name,
+            request.role.as_str(),
+            app_url(),
+            token,
+            INVITATION_DAYS
+        ),
+    };
+
+    // Sent in the background so a slow mail relay does not hold up the response
+    let mailer: web::Data<dyn Mailer> = mailer.clone();
+    actix_web::rt::spawn(async move {
+        if let Err(e) = mailer.send(message).await {
+            eprintln!("{}", e);
+        }
+    });
+
+    HttpResponse::Accepted().json(json!({
+        "email": email,
+        "role": request.role,
+    }))
+}
+
+pub async fn accept_invitation(
+    client: web::Data<Client>,
+    authenticated: AuthenticatedUser,
+    request: web::Json<AcceptInvitation>,
+) -> impl Responder {
+    let db: mongodb::Database = client.database("shortener_link");
+    let invitations: Collection<Invitation> = db.collection("org_invitations");
+    let memberships: Collection<Membership> = db.collection("org_members");
+
+    if authenticated.api_key.is_some() {
+        return HttpResponse::Forbidden().body("API keys cannot manage organizations");
+    }
+
+    let user: &User = &authenticated.user;
+    let user_id: ObjectId = match user.id {
+        Some(id) => id,
+        None => return HttpResponse::InternalServerError().body("User has no ID"),
+    };
+
+    // The invitation is for an address, so the caller must have proven they own it
+    if !user.verified {
+        return HttpResponse::Forbidden().body("Email not verified");
+    }
+
+    let invitation: Invitation = match invitations
+        .find_one_and_delete(doc! {
+            "token_hash": hash_secret_token(&request.token),
+            "email": user.email.trim().to_lowercase(),
+            "expires_at": { "$gt": BsonDateTime::now() },
+        })
+        .await
+    {
+        Ok(Some(invitation)) => invitation,
+        Ok(None) => return HttpResponse::BadRequest().body("Invalid or expired invitation"),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
+
+    match find_membership(&db, invitation.org_id, user_id).await {
+        Ok(Some(_)) => return HttpResponse::Conflict().body("Already a member"),
+        Ok(None) => {}
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+
+    let membership: Membership = Membership {
+        id: None,
+        org_id: invitation.org_id,
+        user_id,
+        role: invitation.role,
+        joined_at: BsonDateTime::now(),
+    };
+
+    if invitation.role == OrgRole::Owner {
+        if let Err(e) = add_owner(&db, invitation.org_id, user_id).await {
+            return HttpResponse::InternalServerError().body(format!("Error: {}", e));
+        }
+    }
+
+    match memberships.insert_one(membership).await {
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "org_id": invitation.org_id.to_hex(),
+            "role": invitation.role,
+        })),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+}
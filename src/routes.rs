@@ -1,8 +1,19 @@
 use actix_web::web;
 
+use crate::api_key::{create_api_key, list_api_keys, revoke_api_key};
+use crate::auth::{forgot_password, me, resend_verification, reset_password, verify_email};
+use crate::keys::jwks;
+use crate::oidc::{oidc_callback, oidc_login};
+use crate::org::{accept_invitation, create_org, invite_member, list_members, list_orgs, remove_member, set_active, update_member};
+use crate::totp::{confirm_totp, setup_totp, verify_totp};
 use crate::user::{ get_users, login_user, refresh_user, register_user, remove_user, update_user};
 
 pub fn public_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/.well-known/jwks.json")
+            .route(web::get().to(jwks))
+    );
+
     cfg.service(
         web::resource("/register")
             .route(web::post().to(register_user))
@@ -13,6 +24,99 @@ pub fn public_routes(cfg: &mut web::ServiceConfig) {
             .route(web::post().to(login_user))
     );
 
+    cfg.service(
+        web::resource("/auth/me")
+            .route(web::get().to(me))
+    );
+
+    cfg.service(
+        web::resource("/auth/api-keys")
+            .route(web::get().to(list_api_keys))
+            .route(web::post().to(create_api_key))
+    );
+
+    cfg.service(
+        web::resource("/auth/api-keys/{id}")
+            .route(web::delete().to(revoke_api_key))
+    );
+
+    cfg.service(
+        web::resource("/auth/oidc/login")
+            .route(web::get().to(oidc_login))
+    );
+
+    cfg.service(
+        web::resource("/auth/oidc/callback")
+            .route(web::get().to(oidc_callback))
+    );
+
+    cfg.service(
+        web::resource("/auth/verify-email")
+            .route(web::post().to(verify_email))
+    );
+
+    cfg.service(
+        web::resource("/auth/resend-verification")
+            .route(web::post().to(resend_verification))
+    );
+
+    cfg.service(
+        web::resource("/auth/forgot-password")
+            .route(web::post().to(forgot_password))
+    );
+
+    cfg.service(
+        web::resource("/auth/reset-password")
+            .route(web::post().to(reset_password))
+    );
+
+    cfg.service(
+        web::resource("/auth/totp/setup")
+            .route(web::post().to(setup_totp))
+    );
+
+    cfg.service(
+        web::resource("/auth/totp/confirm")
+            .route(web::post().to(confirm_totp))
+    );
+
+    cfg.service(
+        web::resource("/auth/totp/verify")
+            .route(web::post().to(verify_totp))
+    );
+
+    cfg.service(
+        web::resource("/orgs")
+            .route(web::get().to(list_orgs))
+            .route(web::post().to(create_org))
+    );
+
+    cfg.service(
+        web::resource("/orgs/active")
+            .route(web::put().to(set_active))
+    );
+
+    cfg.service(
+        web::resource("/orgs/invitations/accept")
+            .route(web::post().to(accept_invitation))
+    );
+
+    cfg.service(
+        web::resource("/orgs/{id}/members")
+            .route(web::get().to(list_members))
+    );
+
+    cfg.service(
+        web::resource("/orgs/{id}/members/{user_id}")
+            .route(web::patch().to(update_member))
+            .route(web::delete().to(remove_member))
+    );
+
+    cfg.service(
+        web::resource("/orgs/{id}/invitations")
+            .route(web::post().to(invite_member))
+    );
+
     cfg.service(
         web::resource("/users")
             .route(web::get().to(get_users))
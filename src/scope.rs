@@ -0,0 +1,79 @@
+use std::fmt;
+
+use actix_web::HttpResponse;
+use serde::{Deserialize, Serialize};
+
+use crate::user::Role;
+
+/// A single permission; access tokens carry them space-separated in the `scope` claim.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    #[serde(rename = "links:read")]
+    LinksRead,
+    #[serde(rename = "links:write")]
+    LinksWrite,
+    #[serde(rename = "links:delete")]
+    LinksDelete,
+    #[serde(rename = "analytics:read")]
+    AnalyticsRead,
+    #[serde(rename = "users:read")]
+    UsersRead,
+    #[serde(rename = "users:admin")]
+    UsersAdmin,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 6] = [
+        Scope::LinksRead,
+        Scope::LinksWrite,
+        Scope::LinksDelete,
+        Scope::AnalyticsRead,
+        Scope::UsersRead,
+        Scope::UsersAdmin,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::LinksRead => "links:read",
+            Scope::LinksWrite => "links:write",
+            Scope::LinksDelete => "links:delete",
+            Scope::AnalyticsRead => "analytics:read",
+            Scope::UsersRead => "users:read",
+            Scope::UsersAdmin => "users:admin",
+        }
+    }
+
+    pub fn parse(scope: &str) -> Option<Scope> {
+        Scope::ALL.into_iter().find(|known| known.as_str() == scope)
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Role {
+    /// Everything a user with this role may do; tokens and API keys only ever narrow it.
+    pub fn scopes(&self) -> &'static [Scope] {
+        match self {
+            Role::Admin => &Scope::ALL,
+            Role::User => &[Scope::LinksRead, Scope::LinksWrite, Scope::LinksDelete, Scope::AnalyticsRead],
+            Role::Auditor => &[Scope::LinksRead, Scope::AnalyticsRead, Scope::UsersRead],
+        }
+    }
+}
+
+/// Space-separated form used in the `scope` claim; unknown entries are dropped.
+pub fn parse_scopes(scopes: &str) -> Vec<Scope> {
+    scopes.split_whitespace().filter_map(Scope::parse).collect()
+}
+
+pub fn join_scopes(scopes: &[Scope]) -> String {
+    scopes.iter().map(Scope::as_str).collect::<Vec<&str>>().join(" ")
+}
+
+pub(crate) fn missing_scope(scope: Scope) -> HttpResponse {
+    HttpResponse::Forbidden().body(format!("Missing scope {}", scope))
+}
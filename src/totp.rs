@@ -0,0 +1,366 @@
+use std::env;
+
+use actix_web::{web, HttpResponse, Responder};
+use chrono::Utc;
+use mongodb::{bson::{doc, oid::ObjectId, DateTime as BsonDateTime}, options::ReturnDocument, Client, Collection, Database};
+use rand::{Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::auth::{hash_secret_token, AuthenticatedUser};
+use crate::jwt::{decode_mfa_token, MfaClaims};
+use crate::lockout::{account_key, clear_failures, locked_until, record_failure};
+use crate::user::{issue_tokens, too_many_attempts, User};
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const TOTP_STEP_SECONDS: i64 = 30;
+/// Codes from this many steps either side of now are accepted, for clock drift.
+const TOTP_SKEW_STEPS: i64 = 1;
+/// Wrong codes one MFA token survives; after that the password has to be entered again.
+const MAX_MFA_TOKEN_FAILURES: i64 = 3;
+
+/// TOTP enrollment of a user; `enabled` is only set once a first code was confirmed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpEnrollment {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    /// Base32-encoded shared secret.
+    pub secret: String,
+    pub enabled: bool,
+    /// SHA-256 hashes of the unused recovery codes.
+    #[serde(default)]
+    pub recovery_code_hashes: Vec<String>,
+    /// Time step of the last accepted code; codes from it or earlier steps are refused.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_time_step: Option<i64>,
+}
+
+/// What has been tried with one MFA token, keyed by its `jti`.
+#[derive(Debug, Serialize, Deserialize)]
+struct MfaTokenUse {
+    #[serde(rename = "_id")]
+    jti: String,
+    expires_at: BsonDateTime,
+    #[serde(default)]
+    failures: i64,
+    /// Completed logins; only the first one counts.
+    #[serde(default)]
+    uses: i64,
+}
+
+impl MfaTokenUse {
+    fn spent(&self) -> bool {
+        self.uses > 0 || self.failures >= MAX_MFA_TOKEN_FAILURES
+    }
+}
+
+/// Adds to the token's counters, creating its record on first use.
+async fn record_mfa_token_use(mfa_tokens: &Collection<MfaTokenUse>, claims: &MfaClaims, field: &str) -> mongodb::error::Result<Option<MfaTokenUse>> {
+    mfa_tokens
+        .find_one_and_update(
+            doc! { "_id": &claims.jti },
+            doc! {
+                "$inc": { field: 1 },
+                "$setOnInsert": { "expires_at": BsonDateTime::from_millis(claims.exp as i64 * 1000) },
+            },
+        )
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .await
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpCodeRequest {
+    pub code: String,
+}
+
+/// Second login step; either a current code or one of the recovery codes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpVerifyRequest {
+    pub mfa_token: String,
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+fn build_totp(secret: &str, account_name: &str) -> Result<TOTP, String> {
+    let bytes: Vec<u8> = Secret::Encoded(secret.to_string()).to_bytes().map_err(|e| format!("{:?}", e))?;
+    let issuer: String = env::var("TOTP_ISSUER").unwrap_or_else(|_| "Link Shortener".to_string());
+
+    // No skew here: `matching_time_step` walks the window itself to learn which step matched
+    TOTP::new(Algorithm::SHA1, 6, 0, TOTP_STEP_SECONDS as u64, bytes, Some(issuer), account_name.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// The time step `code` belongs to, if it is valid now.
+fn matching_time_step(secret: &str, account_name: &str, code: &str) -> Option<i64> {
+    let totp: TOTP = build_totp(secret, account_name).ok()?;
+    let current_step: i64 = Utc::now().timestamp() / TOTP_STEP_SECONDS;
+
+    (current_step - TOTP_SKEW_STEPS..=current_step + TOTP_SKEW_STEPS)
+        .find(|step| totp.check(code.trim(), (step * TOTP_STEP_SECONDS) as u64))
+}
+
+/// Records `time_step` as used, unless a code from it or a later step was already accepted.
+async fn accept_time_step(enrollments: &Collection<TotpEnrollment>, user_id: &ObjectId, time_step: i64) -> mongodb::error::Result<bool> {
+    let update_result = enrollments
+        .update_one(
+            doc! {
+                "user_id": user_id,
+                "enabled": true,
+                "$or": [
+                    { "last_time_step": { "$exists": false } },
+                    { "last_time_step": { "$lt": time_step } },
+                ],
+            },
+            doc! { "$set": { "last_time_step": time_step } },
+        )
+        .await?;
+    Ok(update_result.modified_count > 0)
+}
+
+fn generate_totp_secret() -> String {
+    let mut bytes: [u8; 20] = [0; 20];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    Secret::Raw(bytes.to_vec()).to_encoded().to_string()
+}
+
+fn generate_recovery_code() -> String {
+    let mut rng = rand::thread_rng();
+    let mut code: String = String::with_capacity(11);
+    for i in 0..10 {
+        if i == 5 {
+            code.push('-');
+        }
+        code.push(RECOVERY_CODE_ALPHABET[rng.gen_range(0..RECOVERY_CODE_ALPHABET.len())] as char);
+    }
+    code
+}
+
+/// Recovery codes are compared without dashes, spaces or case.
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hash_secret_token(&normalized)
+}
+
+pub(crate) async fn totp_enabled(db: &Database, user_id: &ObjectId) -> mongodb::error::Result<bool> {
+    let enrollments: Collection<TotpEnrollment> = db.collection("totp");
+    let count: u64 = enrollments
+        .count_documents(doc! { "user_id": user_id, "enabled": true })
+        .await?;
+    Ok(count > 0)
+}
+
+pub async fn setup_totp(
+    client: web::Data<Client>,
+    AuthenticatedUser { user, api_key, .. }: AuthenticatedUser,
+) -> impl Responder {
+    let db: mongodb::Database = client.database("shortener_link");
+    let enrollments: Collection<TotpEnrollment> = db.collection("totp");
+
+    // Only the account holder may change how they sign in
+    if api_key.is_some() {
+        return HttpResponse::Forbidden().body("API keys cannot manage two-factor authentication");
+    }
+
+    let user_id: ObjectId = match user.id {
+        Some(id) => id,
+        None => return HttpResponse::InternalServerError().body("User has no ID"),
+    };
+
+    match totp_enabled(&db, &user_id).await {
+        Ok(false) => {}
+        Ok(true) => return HttpResponse::Conflict().body("Two-factor authentication is already enabled"),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+
+    let secret: String = generate_totp_secret();
+    let totp: TOTP = match build_totp(&secret, &user.email) {
+        Ok(totp) => totp,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
+
+    // Starting over replaces any unconfirmed secret
+    let update_result = enrollments
+        .update_one(
+            doc! { "user_id": user_id },
+            doc! {
+                "$set": {
+                    "secret": &secret,
+                    "enabled": false,
+                    "recovery_code_hashes": [],
+                },
+                "$unset": { "last_time_step": "" },
+            },
+        )
+        .upsert(true)
+        .await;
+
+    match update_result {
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "secret": secret,
+            "otpauth_uri": totp.get_url(),
+        })),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+}
+
+pub async fn confirm_totp(
+    client: web::Data<Client>,
+    AuthenticatedUser { user, api_key, .. }: AuthenticatedUser,
+    request: web::Json<TotpCodeRequest>,
+) -> impl Responder {
+    let db: mongodb::Database = client.database("shortener_link");
+    let enrollments: Collection<TotpEnrollment> = db.collection("totp");
+
+    if api_key.is_some() {
+        return HttpResponse::Forbidden().body("API keys cannot manage two-factor authentication");
+    }
+
+    let user_id: ObjectId = match user.id {
+        Some(id) => id,
+        None => return HttpResponse::InternalServerError().body("User has no ID"),
+    };
+
+    let enrollment: TotpEnrollment = match enrollments.find_one(doc! { "user_id": user_id }).await {
+        Ok(Some(enrollment)) if !enrollment.enabled => enrollment,
+        Ok(Some(_)) => return HttpResponse::Conflict().body("Two-factor authentication is already enabled"),
+        Ok(None) => return HttpResponse::BadRequest().body("Two-factor setup has not been started"),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
+
+    let time_step: i64 = match matching_time_step(&enrollment.secret, &user.email, &request.code) {
+        Some(time_step) => time_step,
+        None => return HttpResponse::BadRequest().body("Invalid code"),
+    };
+
+    // Recovery codes are only ever shown here
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| generate_recovery_code()).collect();
+    let recovery_code_hashes: Vec<String> = recovery_codes.iter().map(|code| hash_recovery_code(code)).collect();
+
+    let update_result = enrollments
+        .update_one(
+            doc! { "user_id": user_id, "enabled": false },
+            doc! {
+                "$set": {
+                    "enabled": true,
+                    "recovery_code_hashes": recovery_code_hashes,
+                    // The confirming code cannot be replayed to sign in
+                    "last_time_step": time_step,
+                }
+            },
+        )
+        .await;
+
+    match update_result {
+        Ok(update_result) if update_result.matched_count > 0 => HttpResponse::Ok().json(json!({
+            "recovery_codes": recovery_codes,
+        })),
+        Ok(_) => HttpResponse::Conflict().body("Two-factor authentication is already enabled"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+}
+
+pub async fn verify_totp(
+    client: web::Data<Client>,
+    request: web::Json<TotpVerifyRequest>,
+) -> impl Responder {
+    let db: mongodb::Database = client.database("shortener_link");
+    let collection: Collection<User> = db.collection("users");
+    let enrollments: Collection<TotpEnrollment> = db.collection("totp");
+    let mfa_tokens: Collection<MfaTokenUse> = db.collection("mfa_tokens");
+
+    let claims: MfaClaims = match decode_mfa_token(&request.mfa_token) {
+        Ok(claims) => claims,
+        Err(_) => return HttpResponse::Unauthorized().body("Invalid or expired MFA token"),
+    };
+
+    let user_id: ObjectId = match ObjectId::parse_str(&claims.sub) {
+        Ok(id) => id,
+        Err(_) => return HttpResponse::Unauthorized().body("Invalid or expired MFA token"),
+    };
+
+    // Checked up front too, so a spent token cannot be used to burn recovery codes
+    match mfa_tokens.find_one(doc! { "_id": &claims.jti }).await {
+        Ok(Some(token_use)) if token_use.spent() => return HttpResponse::Unauthorized().body("Invalid or expired MFA token"),
+        Ok(_) => {}
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+
+    let user: User = match collection.find_one(doc! { "_id": user_id }).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return HttpResponse::Unauthorized().body("Invalid or expired MFA token"),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
+
+    // Codes count towards the same lockout as passwords
+    let account_key: String = account_key(&user.email);
+    match locked_until(&db, std::slice::from_ref(&account_key)).await {
+        Ok(Some(locked_until)) => return too_many_attempts(locked_until),
+        Ok(None) => {}
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+
+    let enrollment: TotpEnrollment = match enrollments.find_one(doc! { "user_id": user_id, "enabled": true }).await {
+        Ok(Some(enrollment)) => enrollment,
+        Ok(None) => return HttpResponse::Unauthorized().body("Invalid or expired MFA token"),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
+
+    let is_valid: bool = match (&request.code, &request.recovery_code) {
+        (Some(code), _) => match matching_time_step(&enrollment.secret, &user.email, code) {
+            // Each code signs in once, and never after a newer one did
+            Some(time_step) => match accept_time_step(&enrollments, &user_id, time_step).await {
+                Ok(accepted) => accepted,
+                Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+            },
+            None => false,
+        },
+        (None, Some(recovery_code)) => {
+            // Pulling the hash out of the list makes every recovery code single-use
+            let recovery_code_hash: String = hash_recovery_code(recovery_code);
+            let update_result = enrollments
+                .update_one(
+                    doc! { "user_id": user_id, "recovery_code_hashes": &recovery_code_hash },
+                    doc! { "$pull": { "recovery_code_hashes": &recovery_code_hash } },
+                )
+                .await;
+
+            match update_result {
+                Ok(update_result) => update_result.modified_count > 0,
+                Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+            }
+        }
+        (None, None) => return HttpResponse::BadRequest().body("A code or recovery code is required"),
+    };
+
+    if !is_valid {
+        if let Err(e) = record_failure(&db, &account_key).await {
+            return HttpResponse::InternalServerError().body(format!("Error: {}", e));
+        }
+        if let Err(e) = record_mfa_token_use(&mfa_tokens, &claims, "failures").await {
+            return HttpResponse::InternalServerError().body(format!("Error: {}", e));
+        }
+        return HttpResponse::Unauthorized().body("Invalid code");
+    }
+
+    // The increment lets only the first request presenting this token through
+    match record_mfa_token_use(&mfa_tokens, &claims, "uses").await {
+        Ok(Some(token_use)) if token_use.uses == 1 && token_use.failures < MAX_MFA_TOKEN_FAILURES => {}
+        Ok(_) => return HttpResponse::Unauthorized().body("Invalid or expired MFA token"),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+
+    // Only a finished login resets the lockout, so failed codes keep escalating it
+    if let Err(e) = clear_failures(&db, &account_key).await {
+        return HttpResponse::InternalServerError().body(format!("Error: {}", e));
+    }
+
+    issue_tokens(&collection, &user).await
+}
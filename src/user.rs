@@ -1,23 +1,36 @@
 use std::time::SystemTime;
 
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::{http::header, web, HttpRequest, HttpResponse, Responder};
 use bcrypt::{hash, verify, DEFAULT_COST};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use futures::StreamExt;
 use serde::{Deserialize, Serialize};
-use mongodb::{bson::{doc, oid::ObjectId, DateTime as BsonDateTime}, Client, Collection};
+use mongodb::{bson::{doc, oid::ObjectId, DateTime as BsonDateTime, Document}, options::{IndexOptions, ReturnDocument}, Client, Collection, IndexModel};
+use mongodb::error::{Error as MongoError, ErrorKind, WriteFailure};
 use chrono::{DateTime, Duration, Utc};
 use serde_json::json;
+use lazy_static::lazy_static;
 use tonic::Request;
 
 use crate::proto::user::{RemoveRequest, UserResponse};
 use crate::proto::user::user_service_client::UserServiceClient;
 
-use crate::jwt::{generate_jwt, generate_refresh_token};
+use crate::auth::{is_subject, send_verification_email, AuthenticatedUser};
+use crate::jwt::{decode_refresh_token, generate_jwt, generate_mfa_token, generate_refresh_token, Claims};
+use crate::lockout::{account_key, clear_failures, client_ip, ip_key, locked_until, record_failure};
+use crate::totp::totp_enabled;
+use crate::mail::Mailer;
+use crate::scope::Scope;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum Role {
     Admin,
-    User
+    User,
+    /// Read-only access to links, analytics and the user list.
+    Auditor,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -42,7 +55,14 @@ pub struct User {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub access_token_expires_at: Option<BsonDateTime>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub refresh_token_expires_at: Option<BsonDateTime>
+    pub refresh_token_expires_at: Option<BsonDateTime>,
+    /// Accounts created before email verification existed have no flag and count as verified.
+    #[serde(default = "default_verified")]
+    pub verified: bool,
+}
+
+fn default_verified() -> bool {
+    true
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -51,16 +71,170 @@ pub struct UserLogin {
     pub password: String
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UserSortField {
+    Id,
+    Username,
+    Email,
+}
+
+impl UserSortField {
+    fn field(&self) -> Option<&'static str> {
+        match self {
+            UserSortField::Id => None,
+            UserSortField::Username => Some("username"),
+            UserSortField::Email => Some("email"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserQuery {
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+    pub role: Option<Role>,
+    pub username_prefix: Option<String>,
+    pub email_prefix: Option<String>,
+    pub sort: Option<UserSortField>,
+    pub order: Option<SortOrder>,
+}
+
+/// Position of the last user on a page, handed to clients as an opaque string.
+#[derive(Serialize, Deserialize)]
+struct PageCursor {
+    sort: UserSortField,
+    id: ObjectId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+}
+
+impl PageCursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    fn decode(cursor: &str) -> Option<PageCursor> {
+        let bytes: Vec<u8> = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+}
+
+fn escape_regex(value: &str) -> String {
+    let mut escaped: String = String::with_capacity(value.len());
+    for c in value.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Partial profile update; fields left out are not touched.
+#[derive(Debug, Serialize, Deserialize, Default)]
 pub struct UpdateUser {
-    pub name: String,
+    pub username: Option<String>,
+    pub email: Option<String>,
+    pub password: Option<String>,
+    /// Required when changing the email or the password.
+    pub current_password: Option<String>,
+}
+
+fn validate_username(username: &str) -> Result<(), &'static str> {
+    let length: usize = username.chars().count();
+    if !(3..=32).contains(&length) {
+        return Err("Username must be between 3 and 32 characters");
+    }
+    if username.chars().any(|c| c.is_control()) || username.trim() != username {
+        return Err("Username contains invalid characters");
+    }
+    Ok(())
+}
+
+fn validate_email(email: &str) -> Result<(), &'static str> {
+    let valid: bool = match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !email.chars().any(|c| c.is_whitespace())
+        }
+        None => false,
+    };
+    if !valid || email.len() > 254 {
+        return Err("Invalid email address");
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_password(password: &str) -> Result<(), &'static str> {
+    if password.chars().count() < 8 {
+        return Err("Password must be at least 8 characters");
+    }
+    // bcrypt only looks at the first 72 bytes
+    if password.len() > 72 {
+        return Err("Password must be at most 72 bytes");
+    }
+    Ok(())
+}
+
+/// Unique indexes behind the conflict checks in `register_user` and `update_user`.
+pub async fn create_user_indexes(collection: &Collection<User>) -> mongodb::error::Result<()> {
+    let indexes: Vec<IndexModel> = ["username", "email"]
+        .into_iter()
+        .map(|field| {
+            IndexModel::builder()
+                .keys(doc! { field: 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build()
+        })
+        .collect();
+
+    collection.create_indexes(indexes).await?;
+    Ok(())
+}
+
+fn is_duplicate_key(error: &MongoError) -> bool {
+    matches!(&*error.kind, ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == 11000)
 }
 
-pub async fn register_user(client: web::Data<mongodb::Client>, new_user: web::Json<User>) -> impl Responder {
+pub async fn register_user(
+    client: web::Data<mongodb::Client>,
+    mailer: web::Data<dyn Mailer>,
+    new_user: web::Json<User>,
+) -> impl Responder {
     let db: mongodb::Database = client.database("shortener_link");
     let collection: Collection<User> = db.collection("users");
 
     let mut new_user_data: User = new_user.into_inner();
+    new_user_data.verified = false;
+    // Elevated roles are granted by an admin, never chosen at sign-up
+    new_user_data.role = Role::User;
+    let email: String = new_user_data.email.clone();
+
+    let validation = validate_username(&new_user_data.username)
+        .and(validate_email(&new_user_data.email))
+        .and(validate_password(&new_user_data.password));
+    if let Err(message) = validation {
+        return HttpResponse::BadRequest().body(message);
+    }
+
+    for (field, value) in [("username", &new_user_data.username), ("email", &new_user_data.email)] {
+        match collection.count_documents(doc! { field: value }).await {
+            Ok(0) => {}
+            Ok(_) => return HttpResponse::Conflict().body(format!("The {} is already in use", field)),
+            Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+        }
+    }
 
     match hash(&new_user_data.password, DEFAULT_COST) {
         Ok(hashed_password) => {
@@ -70,136 +244,416 @@ pub async fn register_user(client: web::Data<mongodb::Client>, new_user: web::Js
     }
 
     match collection.insert_one(new_user_data).await {
-        Ok(insert_result) => HttpResponse::Created().json(insert_result.inserted_id),
+        Ok(insert_result) => {
+            // The account exists either way; a failed email can be resent later
+            if let Some(user_id) = insert_result.inserted_id.as_object_id() {
+                if let Err(e) = send_verification_email(mailer.get_ref(), &user_id, &email).await {
+                    eprintln!("{}", e);
+                }
+            }
+            HttpResponse::Created().json(insert_result.inserted_id)
+        },
+        // Lost a race with a sign-up for the same username or email
+        Err(e) if is_duplicate_key(&e) => HttpResponse::Conflict().body("The username or email is already in use"),
         Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
     }
 }
 
+/// Issues a new access/refresh token pair and stores it on the user document.
+pub(crate) async fn issue_tokens(collection: &Collection<User>, existing_user: &User) -> HttpResponse {
+    // Tokens are keyed by the user ID, which survives email changes
+    let user_id: ObjectId = match existing_user.id {
+        Some(id) => id,
+        None => return HttpResponse::InternalServerError().body("User has no ID"),
+    };
+
+    // Generate JWTs
+    let access_token = match generate_jwt(&user_id.to_hex(), &existing_user.username, &existing_user.role) {
+        Ok(token) => token,
+        Err(_) => return HttpResponse::InternalServerError().body("Error generating access token"),
+    };
+
+    let refresh_token = match generate_refresh_token(&user_id.to_hex(), &existing_user.username, &existing_user.role) {
+        Ok(token) => token,
+        Err(_) => return HttpResponse::InternalServerError().body("Error generating refresh token"),
+    };
+
+    // Set token expiration times
+    let access_token_expires_at: DateTime<Utc> = Utc::now() + Duration::minutes(15);
+    let refresh_token_expires_at: DateTime<Utc> = Utc::now() + Duration::days(7);
+
+    // Convert expiration times to BSON DateTime
+    let access_token_expires_at_system_time: SystemTime = access_token_expires_at.into();
+    let refresh_token_expires_at_system_time: SystemTime = refresh_token_expires_at.into();
+    let access_token_expires_at_bson: BsonDateTime = BsonDateTime::from(access_token_expires_at_system_time);
+    let refresh_token_expires_at_bson: BsonDateTime = BsonDateTime::from(refresh_token_expires_at_system_time);
+
+    // Update user document with tokens and expiration times
+    let update_result = collection
+        .update_one(
+            doc! { "_id": user_id },
+            doc! {
+                "$set": {
+                    "access_token": &access_token,
+                    "refresh_token": &refresh_token,
+                    "access_token_expires_at": access_token_expires_at_bson,
+                    "refresh_token_expires_at": refresh_token_expires_at_bson,
+                }
+            }
+        )
+        .await;
+
+    match update_result {
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "access_token": access_token,
+            "refresh_token": refresh_token,
+            "access_token_expires_at": access_token_expires_at,
+            "refresh_token_expires_at": refresh_token_expires_at,
+        })),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error updating user: {}", e)),
+    }
+}
+
+lazy_static! {
+    /// Checked against when the email is unknown, so both cases take as long.
+    static ref DUMMY_PASSWORD_HASH: String = hash("dummy-password", DEFAULT_COST).expect("Failed to hash the dummy password");
+}
+
+pub(crate) fn too_many_attempts(locked_until: DateTime<Utc>) -> HttpResponse {
+    let retry_after: i64 = (locked_until - Utc::now()).num_seconds().max(1);
+    HttpResponse::TooManyRequests()
+        .insert_header(("Retry-After", retry_after.to_string()))
+        .body("Too many failed login attempts, try again later")
+}
+
+/// Answer to a first login step when the account still needs its second factor.
+pub(crate) fn mfa_challenge(user_id: &ObjectId) -> HttpResponse {
+    match generate_mfa_token(&user_id.to_hex()) {
+        Ok(mfa_token) => HttpResponse::Ok().json(json!({
+            "mfa_required": true,
+            "mfa_token": mfa_token,
+        })),
+        Err(_) => HttpResponse::InternalServerError().body("Error generating MFA token"),
+    }
+}
+
 pub async fn login_user(
+    req: HttpRequest,
     client: web::Data<Client>,
     login_info: web::Json<UserLogin>,
 ) -> impl Responder {
     let db: mongodb::Database = client.database("shortener_link");
     let collection: Collection<User> = db.collection::<User>("users");
 
-    let user: Option<User> = collection
-        .find_one(doc! { "email": &login_info.email })
-        .await
-        .unwrap();
-
-    if let Some(existing_user) = user {
-        match verify(&login_info.password, &existing_user.password) {
-            Ok(is_valid) => {
-                if is_valid {
-
-                    // Generate JWTs
-                    let access_token = match generate_jwt(&existing_user.email,  &existing_user.username, &existing_user.role) {
-                        Ok(token) => token,
-                        Err(_) => return HttpResponse::InternalServerError().body("Error generating access token"),
-                    };
-
-                    let refresh_token = match generate_refresh_token(&existing_user.email,  &existing_user.username, &existing_user.role) {
-                        Ok(token) => token,
-                        Err(_) => return HttpResponse::InternalServerError().body("Error generating refresh token"),
-                    };
-
-                    // Set token expiration times
-                    let access_token_expires_at: DateTime<Utc> = Utc::now() + Duration::minutes(15);
-                    let refresh_token_expires_at: DateTime<Utc> = Utc::now() + Duration::days(7);
-
-                    // Convert expiration times to BSON DateTime
-                    let access_token_expires_at_system_time: SystemTime = access_token_expires_at.into();
-                    let refresh_token_expires_at_system_time: SystemTime = refresh_token_expires_at.into();
-                    let access_token_expires_at_bson: BsonDateTime = BsonDateTime::from(access_token_expires_at_system_time);
-                    let refresh_token_expires_at_bson: BsonDateTime = BsonDateTime::from(refresh_token_expires_at_system_time);
-
-                    // Update user document with tokens and expiration times
-                    let update_result = collection
-                        .update_one(
-                            doc! { "email": &existing_user.email },
-                            doc! {
-                                "$set": {
-                                    "access_token": &access_token,
-                                    "refresh_token": &refresh_token,
-                                    "access_token_expires_at": access_token_expires_at_bson,
-                                    "refresh_token_expires_at": refresh_token_expires_at_bson,
-                                }
-                            }
-                        )
-                        .await;
-
-                    match update_result {
-                        Ok(_) => HttpResponse::Ok().json(json!({
-                            "access_token": access_token,
-                            "refresh_token": refresh_token,
-                            "access_token_expires_at": access_token_expires_at,
-                            "refresh_token_expires_at": refresh_token_expires_at,
-                        })),
-                        Err(e) => HttpResponse::InternalServerError().body(format!("Error updating user: {}", e)),
-                    }
-                } else {
-                    HttpResponse::Unauthorized().body("Invalid credentials")
+    let account_key: String = account_key(&login_info.email);
+    let mut keys: Vec<String> = vec![account_key.clone()];
+    if let Some(ip) = client_ip(&req) {
+        keys.push(ip_key(&ip));
+    }
+
+    match locked_until(&db, &keys).await {
+        Ok(Some(locked_until)) => return too_many_attempts(locked_until),
+        Ok(None) => {}
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+
+    let user: Option<User> = match collection.find_one(doc! { "email": &login_info.email }).await {
+        Ok(user) => user,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
+
+    let password_hash: &str = match &user {
+        Some(existing_user) => &existing_user.password,
+        None => &DUMMY_PASSWORD_HASH,
+    };
+
+    let is_valid: bool = match verify(&login_info.password, password_hash) {
+        Ok(is_valid) => is_valid,
+        Err(_) => return HttpResponse::InternalServerError().body("Error verifying password"),
+    };
+
+    // Unknown emails and wrong passwords look the same to the caller
+    let existing_user: User = match user {
+        Some(existing_user) if is_valid => existing_user,
+        _ => {
+            for key in &keys {
+                if let Err(e) = record_failure(&db, key).await {
+                    return HttpResponse::InternalServerError().body(format!("Error: {}", e));
                 }
             }
-            Err(_) => HttpResponse::InternalServerError().body("Error verifying password"),
+            return HttpResponse::Unauthorized().body("Invalid credentials");
         }
-    } else {
-        HttpResponse::Unauthorized().body("User not found")
+    };
+
+    if !existing_user.verified {
+        return HttpResponse::Forbidden().body("Email not verified");
     }
+
+    // Accounts with two-factor authentication finish logging in at /auth/totp/verify
+    let user_id: ObjectId = match existing_user.id {
+        Some(id) => id,
+        None => return HttpResponse::InternalServerError().body("User has no ID"),
+    };
+    match totp_enabled(&db, &user_id).await {
+        Ok(true) => return mfa_challenge(&user_id),
+        Ok(false) => {}
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    }
+
+    // Cleared only once the login is complete; a pending second factor keeps the count
+    if let Err(e) = clear_failures(&db, &account_key).await {
+        return HttpResponse::InternalServerError().body(format!("Error: {}", e));
+    }
+
+    issue_tokens(&collection, &existing_user).await
 }
 
-pub async fn get_users(client: web::Data<Client>) -> impl Responder {
+pub async fn get_users(
+    client: web::Data<Client>,
+    authenticated: AuthenticatedUser,
+    query: web::Query<UserQuery>,
+) -> impl Responder {
+    if let Err(response) = authenticated.require(Scope::UsersRead) {
+        return response;
+    }
+
     let db: mongodb::Database = client.database("shortener_link");
     let collection: Collection<User> = db.collection("users");
 
-    let mut cursor: mongodb::Cursor<User> = collection.find(doc! {}).await.unwrap();
-    let mut users: Vec<UserSend> = Vec::new();
+    let query: UserQuery = query.into_inner();
+    let limit: i64 = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let sort: UserSortField = query.sort.unwrap_or(UserSortField::Id);
+    let direction: i32 = match query.order.unwrap_or(SortOrder::Asc) {
+        SortOrder::Asc => 1,
+        SortOrder::Desc => -1,
+    };
+
+    // Filters shared by the page query and the total count
+    let mut filter: Document = doc! {};
+    if let Some(role) = &query.role {
+        match mongodb::bson::to_bson(role) {
+            Ok(role) => { filter.insert("role", role); },
+            Err(_) => return HttpResponse::BadRequest().body("Invalid role"),
+        }
+    }
+    if let Some(prefix) = &query.username_prefix {
+        filter.insert("username", doc! { "$regex": format!("^{}", escape_regex(prefix)) });
+    }
+    if let Some(prefix) = &query.email_prefix {
+        filter.insert("email", doc! { "$regex": format!("^{}", escape_regex(prefix)) });
+    }
+
+    let total: u64 = match collection.count_documents(filter.clone()).await {
+        Ok(total) => total,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
+
+    // Resume after the last user of the previous page
+    if let Some(cursor) = &query.cursor {
+        let cursor: PageCursor = match PageCursor::decode(cursor) {
+            Some(cursor) if cursor.sort == sort => cursor,
+            _ => return HttpResponse::BadRequest().body("Invalid cursor"),
+        };
+        let op: &str = if direction == 1 { "$gt" } else { "$lt" };
+
+        match (sort.field(), cursor.key) {
+            (None, _) => {
+                filter.insert("_id", doc! { op: cursor.id });
+            }
+            (Some(field), Some(key)) => {
+                filter.insert("$or", vec![
+                    doc! { field: { op: &key } },
+                    doc! { field: &key, "_id": { op: cursor.id } },
+                ]);
+            }
+            (Some(_), None) => return HttpResponse::BadRequest().body("Invalid cursor"),
+        }
+    }
+
+    let sort_doc: Document = match sort.field() {
+        Some(field) => doc! { field: direction, "_id": direction },
+        None => doc! { "_id": direction },
+    };
+
+    // Fetch one extra user to know whether another page follows
+    let mut cursor: mongodb::Cursor<User> = match collection
+        .find(filter)
+        .sort(sort_doc)
+        .limit(limit + 1)
+        .await
+    {
+        Ok(cursor) => cursor,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
+
+    let mut users: Vec<User> = Vec::new();
 
     while let Some(result) = cursor.next().await {
         match result {
-            Ok(user) => {
-                users.push(UserSend {
-                    id: user.id,
-                    username: user.username,
-                    role: user.role,
-                });
-            }
+            Ok(user) => users.push(user),
             Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e))
         }
     }
 
-    HttpResponse::Ok().json(users)
+    let next_cursor: Option<String> = if users.len() as i64 > limit {
+        users.truncate(limit as usize);
+        users.last().and_then(|last| {
+            let key: Option<String> = match sort {
+                UserSortField::Id => None,
+                UserSortField::Username => Some(last.username.clone()),
+                UserSortField::Email => Some(last.email.clone()),
+            };
+            last.id.map(|id| PageCursor { sort, id, key }.encode())
+        })
+    } else {
+        None
+    };
+
+    let users: Vec<UserSend> = users
+        .into_iter()
+        .map(|user| UserSend {
+            id: user.id,
+            username: user.username,
+            role: user.role,
+        })
+        .collect();
+
+    let mut response = HttpResponse::Ok();
+    response.insert_header(("X-Total-Count", total.to_string()));
+    if let Some(next_cursor) = next_cursor {
+        response.insert_header(("X-Next-Cursor", next_cursor));
+    }
+
+    response.json(users)
 }
 
 pub async fn update_user(
     client: web::Data<Client>,
+    mailer: web::Data<dyn Mailer>,
+    authenticated: AuthenticatedUser,
     user_id: web::Path<String>,
-    new_name: web::Json<UpdateUser>,
+    changes: web::Json<UpdateUser>,
 ) -> impl Responder {
     let db: mongodb::Database = client.database("shortener_link");
     let collection: Collection<User> = db.collection("users");
 
-    let object_id: ObjectId = match ObjectId::parse_str(&user_id.into_inner()) {
+    let object_id: ObjectId = match ObjectId::parse_str(user_id.into_inner()) {
         Ok(id) => id,
         Err(_) => return HttpResponse::BadRequest().body("Invalid user ID"),
     };
 
-    let filter: mongodb::bson::Document = doc! { "_id": object_id };
-    let update: mongodb::bson::Document = doc! { "$set": { "name": &new_name.name } };
+    // Profiles are changed from a signed-in session, never with an API key
+    if authenticated.api_key.is_some() {
+        return HttpResponse::Forbidden().body("API keys cannot manage user profiles");
+    }
 
-    match collection.update_one(filter, update).await {
-        Ok(update_result) => {
-            if update_result.matched_count > 0 {
-                HttpResponse::Ok().body("User updated successfully")
-            } else {
-                HttpResponse::NotFound().body("User not found")
+    // Anyone may edit their own profile; editing others takes users:admin
+    if authenticated.user.id != Some(object_id) {
+        if let Err(response) = authenticated.require(Scope::UsersAdmin) {
+            return response;
+        }
+    }
+
+    let changes: UpdateUser = changes.into_inner();
+
+    if changes.username.is_none() && changes.email.is_none() && changes.password.is_none() {
+        return HttpResponse::BadRequest().body("No fields to update");
+    }
+
+    let validation = changes.username.as_deref().map(validate_username).transpose()
+        .and(changes.email.as_deref().map(validate_email).transpose())
+        .and(changes.password.as_deref().map(validate_password).transpose());
+    if let Err(message) = validation {
+        return HttpResponse::BadRequest().body(message);
+    }
+
+    let existing_user: User = match collection.find_one(doc! { "_id": object_id }).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return HttpResponse::NotFound().body("User not found"),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
+
+    // Email and password changes must be confirmed with the current password
+    if changes.email.is_some() || changes.password.is_some() {
+        let current_password: &str = match changes.current_password.as_deref() {
+            Some(password) => password,
+            None => return HttpResponse::BadRequest().body("Current password is required"),
+        };
+
+        match verify(current_password, &existing_user.password) {
+            Ok(true) => {}
+            Ok(false) => return HttpResponse::Unauthorized().body("Invalid current password"),
+            Err(_) => return HttpResponse::InternalServerError().body("Error verifying password"),
+        }
+    }
+
+    let mut set: Document = doc! {};
+    let mut unset: Document = doc! {};
+
+    for (field, value) in [("username", &changes.username), ("email", &changes.email)] {
+        let Some(value) = value else { continue };
+
+        let taken = collection
+            .count_documents(doc! { field: value, "_id": { "$ne": object_id } })
+            .await;
+
+        match taken {
+            Ok(0) => { set.insert(field, value); },
+            Ok(_) => return HttpResponse::Conflict().body(format!("The {} is already in use", field)),
+            Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+        }
+    }
+
+    if let Some(password) = &changes.password {
+        match hash(password, DEFAULT_COST) {
+            Ok(hashed_password) => { set.insert("password", hashed_password); },
+            Err(_) => return HttpResponse::InternalServerError().body("Failed to hash the password"),
+        }
+
+        // Force every session to log in again with the new password
+        unset.insert("access_token", "");
+        unset.insert("refresh_token", "");
+        unset.insert("access_token_expires_at", "");
+        unset.insert("refresh_token_expires_at", "");
+    }
+
+    // A new email address has to be verified again
+    if changes.email.is_some() {
+        set.insert("verified", false);
+    }
+
+    let mut update: Document = doc! { "$set": set };
+    if !unset.is_empty() {
+        update.insert("$unset", unset);
+    }
+
+    let update_result = collection
+        .find_one_and_update(doc! { "_id": object_id }, update)
+        .return_document(ReturnDocument::After)
+        .await;
+
+    match update_result {
+        Ok(Some(user)) => {
+            if let (Some(email), Some(user_id)) = (&changes.email, &user.id) {
+                if let Err(e) = send_verification_email(mailer.get_ref(), user_id, email).await {
+                    eprintln!("{}", e);
+                }
             }
+
+            HttpResponse::Ok().json(UserSend {
+                id: user.id,
+                username: user.username,
+                role: user.role,
+            })
         },
+        Ok(None) => HttpResponse::NotFound().body("User not found"),
+        Err(e) if is_duplicate_key(&e) => HttpResponse::Conflict().body("The username or email is already in use"),
         Err(e) => HttpResponse::InternalServerError().body(format!("Error: {}", e)),
     }
 }
 
 pub async fn refresh_user(
+    req: HttpRequest,
     client: web::Data<Client>,
     user_id: web::Path<String>,
 ) -> impl Responder {
@@ -207,64 +661,54 @@ pub async fn refresh_user(
     let collection: Collection<User> = db.collection::<User>("users");
 
     // Parse the user ID into an ObjectId
-    let object_id: ObjectId = match ObjectId::parse_str(&user_id.into_inner()) {
+    let object_id: ObjectId = match ObjectId::parse_str(user_id.into_inner()) {
         Ok(id) => id,
         Err(_) => return HttpResponse::BadRequest().body("Invalid user ID"),
     };
 
-    // Find the user in the database
-    let user: Option<User> = collection
-        .find_one(doc! { "_id": &object_id }) // Corrected field name to "_id"
-        .await
-        .unwrap();
-
-    if let Some(existing_user) = user {
-
-        let access_token = match generate_jwt(&existing_user.email, &existing_user.username, &existing_user.role) {
-            Ok(token) => token,
-            Err(_) => return HttpResponse::InternalServerError().body("Error generating access token"),
-        };
+    // The refresh token comes in the Authorization header
+    let refresh_token: &str = match req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    {
+        Some(token) => token,
+        None => return HttpResponse::Unauthorized().body("Authorization header missing or invalid"),
+    };
 
-        let refresh_token = match generate_refresh_token(&existing_user.email, &existing_user.username, &existing_user.role) {
-            Ok(token) => token,
-            Err(_) => return HttpResponse::InternalServerError().body("Error generating refresh token"),
-        };
+    let claims: Claims = match decode_refresh_token(refresh_token) {
+        Ok(claims) => claims,
+        Err(_) => return HttpResponse::Unauthorized().body("Invalid refresh token"),
+    };
 
-        let access_token_expires_at: DateTime<Utc> = Utc::now() + Duration::minutes(15);
-        let refresh_token_expires_at: DateTime<Utc> = Utc::now() + Duration::days(7);
-
-        let update_result = collection
-            .update_one(
-                doc! { "_id": &object_id },
-                doc! {
-                    "$set": {
-                        "access_token": &access_token,
-                        "refresh_token": &refresh_token,
-                        "access_token_expires_at": access_token_expires_at.timestamp(),
-                        "refresh_token_expires_at": refresh_token_expires_at.timestamp(),
-                    }
-                },
-            )
-            .await;
+    // Find the user in the database
+    let user: Option<User> = match collection.find_one(doc! { "_id": &object_id }).await {
+        Ok(user) => user,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error: {}", e)),
+    };
 
-        match update_result {
-            Ok(_) => HttpResponse::Ok().json(json!({
-                "access_token": access_token,
-                "refresh_token": refresh_token,
-                "access_token_expires_at": access_token_expires_at,
-                "refresh_token_expires_at": refresh_token_expires_at,
-            })),
-            Err(e) => HttpResponse::InternalServerError().body(format!("Error updating user: {}", e)),
+    match user {
+        // Only the latest refresh token of this user is accepted
+        Some(existing_user) if is_subject(&claims.sub, &existing_user)
+            && existing_user.refresh_token.as_deref() == Some(refresh_token) =>
+        {
+            issue_tokens(&collection, &existing_user).await
         }
-    } else {
-        HttpResponse::NotFound().body("User not found")
+        Some(_) => HttpResponse::Unauthorized().body("Invalid refresh token"),
+        None => HttpResponse::NotFound().body("User not found"),
     }
 }
 
 pub async fn remove_user(
     grpc_client: web::Data<UserServiceClient<tonic::transport::Channel>>, // gRPC client
+    authenticated: AuthenticatedUser,
     user_id: web::Path<String>,
 ) -> impl Responder {
+    if let Err(response) = authenticated.require(Scope::UsersAdmin) {
+        return response;
+    }
+
     let id: String = user_id.into_inner();
     println!("entra");
     // Validate and parse ObjectId
@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::{cookie::Cookie, dev::ServiceResponse, web, App, HttpResponse, HttpServer};
+use api::jwt::generate_jwt;
+use api::keys::JwtKeys;
+use api::mail::{Mailer, MemoryMailer};
+use api::user::{Role, User};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::Algorithm;
+use mongodb::{bson::{doc, oid::ObjectId}, Client, Collection};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+/// In-memory mailer plus the app data handlers expect, so tests can read sent emails.
+pub fn memory_mailer() -> (Arc<MemoryMailer>, web::Data<dyn Mailer>) {
+    let mailer: Arc<MemoryMailer> = Arc::new(MemoryMailer::default());
+    let data: web::Data<dyn Mailer> = web::Data::from(mailer.clone() as Arc<dyn Mailer>);
+    (mailer, data)
+}
+
+/// Pulls the `token` query parameter out of the last email sent to `to`.
+pub fn token_from_last_email(mailer: &MemoryMailer, to: &str) -> String {
+    let email = mailer
+        .sent()
+        .into_iter()
+        .rev()
+        .find(|email| email.to == to)
+        .expect("no email was sent");
+
+    token_in(&email.body)
+}
+
+/// Like `token_from_last_email`, for mail sent in the background: waits for an email to
+/// `to` with `subject` to arrive.
+pub async fn token_from_email(mailer: &MemoryMailer, to: &str, subject: &str) -> String {
+    for _ in 0..50 {
+        if let Some(email) = mailer.sent().into_iter().rev().find(|email| email.to == to && email.subject == subject) {
+            return token_in(&email.body);
+        }
+        actix_rt::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    panic!("no email was sent")
+}
+
+fn token_in(body: &str) -> String {
+    body
+        .split("token=")
+        .nth(1)
+        .and_then(|rest| rest.split_whitespace().next())
+        .expect("email does not contain a token")
+        .to_string()
+}
+
+/// The state cookie `/auth/oidc/login` sets, to send back with the callback.
+pub fn oidc_state_cookie(response: &ServiceResponse) -> Cookie<'static> {
+    response
+        .response()
+        .cookies()
+        .find(|cookie| cookie.name() == "oidc_state")
+        .expect("no state cookie was set")
+        .into_owned()
+}
+
+/// Inserts a verified user with `role` and returns its ID and a valid access token.
+pub async fn signed_in_user(client: &Client, role: Role) -> (ObjectId, String) {
+    let collection: Collection<User> = client.database("shortener_link").collection("users");
+    let prefix: String = ObjectId::new().to_hex();
+
+    let user: User = User {
+        id: None,
+        username: prefix.clone(),
+        email: format!("{}@example.com", prefix),
+        password: String::new(),
+        role: role.clone(),
+        access_token: None,
+        refresh_token: None,
+        access_token_expires_at: None,
+        refresh_token_expires_at: None,
+        verified: true,
+    };
+
+    let user_id: ObjectId = collection.insert_one(user).await.unwrap().inserted_id.as_object_id().unwrap();
+    let access_token: String = generate_jwt(&user_id.to_hex(), &prefix, &role).unwrap();
+
+    collection
+        .update_one(doc! { "_id": user_id }, doc! { "$set": { "access_token": &access_token } })
+        .await
+        .unwrap();
+
+    (user_id, access_token)
+}
+
+/// What the mock provider puts in the next ID token, plus the codes it has handed out.
+#[derive(Default)]
+pub struct MockOidcState {
+    pub subject: String,
+    pub email: String,
+    pub groups: Vec<String>,
+    /// Times the JWKS was downloaded.
+    pub jwks_requests: usize,
+    /// code -> (nonce, code_challenge)
+    codes: HashMap<String, (String, String)>,
+}
+
+/// A local OpenID provider with discovery, JWKS and token endpoints, signing with the RSA fixture.
+pub struct MockOidc {
+    pub issuer: String,
+    pub state: Arc<Mutex<MockOidcState>>,
+}
+
+impl MockOidc {
+    /// Plays the user approving the login at `authorization_url` and returns the callback query.
+    pub fn authorize(&self, authorization_url: &str) -> String {
+        let url: Url = Url::parse(authorization_url).unwrap();
+        let param = |name: &str| url.query_pairs().find(|(key, _)| key == name).map(|(_, value)| value.into_owned()).unwrap();
+
+        assert_eq!(param("code_challenge_method"), "S256");
+
+        let code: String = ObjectId::new().to_hex();
+        self.state.lock().unwrap().codes.insert(code.clone(), (param("nonce"), param("code_challenge")));
+        format!("code={}&state={}", code, param("state"))
+    }
+}
+
+#[derive(Deserialize)]
+struct MockTokenRequest {
+    code: String,
+    code_verifier: String,
+    client_id: String,
+}
+
+pub async fn start_mock_oidc(client_id: &str) -> MockOidc {
+    let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let issuer: String = format!("http://{}", listener.local_addr().unwrap());
+    let state: Arc<Mutex<MockOidcState>> = Arc::new(Mutex::new(MockOidcState::default()));
+
+    let keys: web::Data<JwtKeys> = web::Data::new(JwtKeys::from_pem(
+        "mock-idp",
+        Algorithm::RS256,
+        include_bytes!("../fixtures/rsa_private.pem"),
+        include_bytes!("../fixtures/rsa_public.pem"),
+    ).unwrap());
+    let app_state: web::Data<Mutex<MockOidcState>> = web::Data::from(state.clone());
+    let app_issuer: String = issuer.clone();
+    let client_id: String = client_id.to_string();
+
+    let server = HttpServer::new(move || {
+        let issuer: String = app_issuer.clone();
+        let discovery_issuer: String = app_issuer.clone();
+        let client_id: String = client_id.clone();
+
+        App::new()
+            .app_data(keys.clone())
+            .app_data(app_state.clone())
+            .route("/.well-known/openid-configuration", web::get().to(move || {
+                let issuer: String = discovery_issuer.clone();
+                async move {
+                    HttpResponse::Ok().json(json!({
+                        "issuer": issuer,
+                        "authorization_endpoint": format!("{}/authorize", issuer),
+                        "token_endpoint": format!("{}/token", issuer),
+                        "jwks_uri": format!("{}/jwks", issuer),
+                    }))
+                }
+            }))
+            .route("/jwks", web::get().to(|keys: web::Data<JwtKeys>, state: web::Data<Mutex<MockOidcState>>| async move {
+                state.lock().unwrap().jwks_requests += 1;
+                HttpResponse::Ok().json(keys.jwks())
+            }))
+            .route("/token", web::post().to(move |keys: web::Data<JwtKeys>, state: web::Data<Mutex<MockOidcState>>, form: web::Form<MockTokenRequest>| {
+                let issuer: String = issuer.clone();
+                let client_id: String = client_id.clone();
+                async move {
+                    let mut state = state.lock().unwrap();
+                    let (nonce, challenge) = match state.codes.remove(&form.code) {
+                        Some(pending) => pending,
+                        None => return HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" })),
+                    };
+
+                    // PKCE: the verifier must hash to the challenge sent with the login
+                    let verified: bool = URL_SAFE_NO_PAD.encode(Sha256::digest(form.code_verifier.as_bytes())) == challenge;
+                    if !verified || form.client_id != client_id {
+                        return HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" }));
+                    }
+
+                    let now: i64 = chrono::Utc::now().timestamp();
+                    let id_token: String = keys.sign(&json!({
+                        "iss": issuer,
+                        "aud": client_id,
+                        "sub": state.subject,
+                        "iat": now,
+                        "exp": now + 300,
+                        "nonce": nonce,
+                        "email": state.email,
+                        "email_verified": true,
+                        "groups": state.groups,
+                    })).unwrap();
+
+                    HttpResponse::Ok().json(json!({
+                        "access_token": "mock-access-token",
+                        "token_type": "Bearer",
+                        "id_token": id_token,
+                    }))
+                }
+            }))
+    })
+    .listen(listener)
+    .unwrap()
+    .workers(1)
+    .run();
+
+    actix_rt::spawn(server);
+
+    MockOidc { issuer, state }
+}
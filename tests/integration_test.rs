@@ -1,8 +1,16 @@
-use actix_web::{http::StatusCode, test, web, App};
+use actix_web::{cookie::Cookie, http::StatusCode, test, web, App};
 use api::*;
-use mongodb::{options::ClientOptions, Client};
+use mongodb::{bson::doc, options::ClientOptions, Client, Collection};
 use serde::{Deserialize, Serialize};
-use user::{login_user, register_user, User, UserLogin, Role};
+use api_key::{create_api_key, list_api_keys, revoke_api_key, CreateApiKey};
+use auth::{forgot_password, me, reset_password, verify_email, ForgotPasswordRequest, ResetPasswordRequest, VerifyEmailRequest};
+use jsonwebtoken::{Algorithm, Validation};
+use keys::JwtKeys;
+use oidc::{oidc_callback, oidc_login, OidcConfig, OidcProvider};
+use org::{accept_invitation, create_org, invite_member, list_members, list_orgs, remove_member, set_active, update_member, AcceptInvitation, CreateOrganization, InviteMember, OrgRole, SetActiveOrg, UpdateMember};
+use scope::Scope;
+use totp::{confirm_totp, setup_totp, verify_totp, TotpCodeRequest, TotpEnrollment, TotpVerifyRequest};
+use user::{get_users, login_user, register_user, update_user, UpdateUser, User, UserLogin, Role};
 
 mod common;
 
@@ -35,22 +43,28 @@ pub struct AuthResponse {
 async fn test_register_user() {
 
     let client: Client = setup().await;
-    
+
+    let prefix: String = mongodb::bson::oid::ObjectId::new().to_hex();
+
     let new_user: User = User {
         id: None,
-        username: "Test User".to_string(),
-        email: "test@example.com".to_string(),
+        username: prefix.clone(),
+        email: format!("{}@example.com", prefix),
         password: "password123".to_string(),
         role: user::Role::User,
         access_token: None,
         refresh_token: None,
         access_token_expires_at: None,
         refresh_token_expires_at: None,
+        verified: false,
     };
 
+    let (_, mailer) = common::memory_mailer();
+
     let app = test::init_service(
         App::new()
             .app_data(web::Data::new(client.clone()))
+            .app_data(mailer.clone())
             .service(web::resource("/register").route(web::post().to(register_user)))
     ).await;
 
@@ -63,6 +77,22 @@ async fn test_register_user() {
 
     assert_eq!(resp.status(), StatusCode::CREATED);
 
+    // Usernames and emails are unique
+    let duplicate_req = test::TestRequest::post()
+        .uri("/register")
+        .set_json(&new_user)
+        .to_request();
+    let duplicate_resp: actix_web::dev::ServiceResponse = test::call_service(&app, duplicate_req).await;
+    assert_eq!(duplicate_resp.status(), StatusCode::CONFLICT);
+
+    // Sign-up applies the same rules as profile updates
+    let short_req = test::TestRequest::post()
+        .uri("/register")
+        .set_json(User { username: format!("{}-short", prefix), email: format!("{}-short@example.com", prefix), password: "short".to_string(), ..new_user })
+        .to_request();
+    let short_resp: actix_web::dev::ServiceResponse = test::call_service(&app, short_req).await;
+    assert_eq!(short_resp.status(), StatusCode::BAD_REQUEST);
+
     teardown(&client);
 }
 
@@ -70,30 +100,37 @@ async fn test_register_user() {
 async fn test_login_user() {
 
     let client: Client = setup().await;
-    
+
+    let prefix: String = mongodb::bson::oid::ObjectId::new().to_hex();
+
     let new_user: User = User {
         id: None,
-        username: "Test User".to_string(),
-        email: "test@example.com".to_string(),
+        username: prefix.clone(),
+        email: format!("{}@example.com", prefix),
         password: "password123".to_string(),
         role: user::Role::User,
         access_token: None,
         refresh_token: None,
         access_token_expires_at: None,
         refresh_token_expires_at: None,
+        verified: false,
     };
 
 
     let user: UserLogin = UserLogin {
-        email: "test@example.com".to_string(),
+        email: format!("{}@example.com", prefix),
         password: "password123".to_string(),
     };
 
+    let (sent_mail, mailer) = common::memory_mailer();
+
     let app = test::init_service(
         App::new()
             .app_data(web::Data::new(client.clone()))
+            .app_data(mailer.clone())
             .service(web::resource("/register").route(web::post().to(register_user)))
             .service(web::resource("/login").route(web::post().to(login_user)))
+            .service(web::resource("/auth/verify-email").route(web::post().to(verify_email)))
     ).await;
 
     let req1 = test::TestRequest::post()
@@ -107,6 +144,13 @@ async fn test_login_user() {
         .to_request();
 
     let _: actix_web::dev::ServiceResponse= test::call_service(&app, req1).await;
+
+    let verify_req = test::TestRequest::post()
+        .uri("/auth/verify-email")
+        .set_json(VerifyEmailRequest { token: common::token_from_last_email(&sent_mail, &new_user.email) })
+        .to_request();
+    let _: actix_web::dev::ServiceResponse= test::call_service(&app, verify_req).await;
+
     let resp2: actix_web::dev::ServiceResponse= test::call_service(&app, req2).await;
 
     assert_eq!(resp2.status(), StatusCode::OK);
@@ -120,28 +164,34 @@ async fn test_login_user() {
 async fn test_get_users() {
 
     let client: Client = setup().await;
-    
+
+    let prefix: String = mongodb::bson::oid::ObjectId::new().to_hex();
+
     let new_user: User = User {
         id: None,
-        username: "Test User".to_string(),
-        email: "test@example.com".to_string(),
+        username: prefix.clone(),
+        email: format!("{}@example.com", prefix),
         password: "password123".to_string(),
         role: user::Role::User,
         access_token: None,
         refresh_token: None,
         access_token_expires_at: None,
         refresh_token_expires_at: None,
+        verified: false,
     };
 
 
     let user: UserLogin = UserLogin {
-        email: "test@example.com".to_string(),
+        email: format!("{}@example.com", prefix),
         password: "password123".to_string(),
     };
 
+    let (_, mailer) = common::memory_mailer();
+
     let app = test::init_service(
         App::new()
             .app_data(web::Data::new(client.clone()))
+            .app_data(mailer.clone())
             .service(web::resource("/register").route(web::post().to(register_user)))
             .service(web::resource("/login").route(web::post().to(login_user)))
     ).await;
@@ -167,4 +217,1265 @@ async fn test_get_users() {
     println!("{:?}", resp2);
     let body: web::Bytes = test::read_body(resp2).await;
 
-}
\ No newline at end of file
+}
+#[actix_rt::test]
+async fn test_get_users_pagination() {
+
+    let client: Client = setup().await;
+
+    let prefix: String = mongodb::bson::oid::ObjectId::new().to_hex();
+
+    let (_, mailer) = common::memory_mailer();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(client.clone()))
+            .app_data(mailer.clone())
+            .service(web::resource("/register").route(web::post().to(register_user)))
+            .service(web::resource("/users").route(web::get().to(get_users)))
+    ).await;
+
+    let (_, auditor_token) = common::signed_in_user(&client, user::Role::Auditor).await;
+
+    for i in 0..3 {
+        let new_user: User = User {
+            id: None,
+            username: format!("{}-{}", prefix, i),
+            email: format!("{}-{}@example.com", prefix, i),
+            password: "password123".to_string(),
+            role: user::Role::User,
+            access_token: None,
+            refresh_token: None,
+            access_token_expires_at: None,
+            refresh_token_expires_at: None,
+            verified: false,
+        };
+
+        let req = test::TestRequest::post()
+            .uri("/register")
+            .set_json(&new_user)
+            .to_request();
+
+        let _: actix_web::dev::ServiceResponse = test::call_service(&app, req).await;
+    }
+
+    let req1 = test::TestRequest::get()
+        .uri(&format!("/users?username_prefix={}&sort=username&limit=2", prefix))
+        .insert_header(("Authorization", format!("Bearer {}", auditor_token)))
+        .to_request();
+
+    let resp1: actix_web::dev::ServiceResponse = test::call_service(&app, req1).await;
+
+    assert_eq!(resp1.status(), StatusCode::OK);
+    assert_eq!(resp1.headers().get("X-Total-Count").unwrap(), "3");
+
+    let next_cursor: String = resp1.headers().get("X-Next-Cursor").unwrap().to_str().unwrap().to_string();
+    let page1: Vec<serde_json::Value> = test::read_body_json(resp1).await;
+    assert_eq!(page1.len(), 2);
+    assert_eq!(page1[0]["username"], format!("{}-0", prefix));
+
+    let req2 = test::TestRequest::get()
+        .uri(&format!("/users?username_prefix={}&sort=username&limit=2&cursor={}", prefix, next_cursor))
+        .insert_header(("Authorization", format!("Bearer {}", auditor_token)))
+        .to_request();
+
+    let resp2: actix_web::dev::ServiceResponse = test::call_service(&app, req2).await;
+
+    assert_eq!(resp2.status(), StatusCode::OK);
+    assert!(resp2.headers().get("X-Next-Cursor").is_none());
+
+    let page2: Vec<serde_json::Value> = test::read_body_json(resp2).await;
+    assert_eq!(page2.len(), 1);
+    assert_eq!(page2[0]["username"], format!("{}-2", prefix));
+
+    teardown(&client).await;
+}
+
+#[actix_rt::test]
+async fn test_update_user_profile() {
+
+    let client: Client = setup().await;
+
+    let prefix: String = mongodb::bson::oid::ObjectId::new().to_hex();
+
+    let new_user: User = User {
+        id: None,
+        username: format!("{}-before", prefix),
+        email: format!("{}@example.com", prefix),
+        password: "password123".to_string(),
+        role: user::Role::User,
+        access_token: None,
+        refresh_token: None,
+        access_token_expires_at: None,
+        refresh_token_expires_at: None,
+        verified: false,
+    };
+
+    let (sent_mail, mailer) = common::memory_mailer();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(client.clone()))
+            .app_data(mailer.clone())
+            .service(web::resource("/register").route(web::post().to(register_user)))
+            .service(web::resource("/login").route(web::post().to(login_user)))
+            .service(web::resource("/auth/verify-email").route(web::post().to(verify_email)))
+            .service(web::resource("/users/{id}").route(web::patch().to(update_user)))
+    ).await;
+
+    let req1 = test::TestRequest::post()
+        .uri("/register")
+        .set_json(&new_user)
+        .to_request();
+
+    let resp1: actix_web::dev::ServiceResponse = test::call_service(&app, req1).await;
+    let inserted_id: serde_json::Value = test::read_body_json(resp1).await;
+    let user_id: &str = inserted_id["$oid"].as_str().unwrap();
+
+    let verify_req = test::TestRequest::post()
+        .uri("/auth/verify-email")
+        .set_json(VerifyEmailRequest { token: common::token_from_last_email(&sent_mail, &new_user.email) })
+        .to_request();
+    let _: actix_web::dev::ServiceResponse = test::call_service(&app, verify_req).await;
+
+    let login_req = test::TestRequest::post()
+        .uri("/login")
+        .set_json(UserLogin { email: new_user.email.clone(), password: "password123".to_string() })
+        .to_request();
+    let tokens: AuthResponse = test::call_and_read_body_json(&app, login_req).await;
+
+    // Other users cannot edit the profile
+    let (_, other_token) = common::signed_in_user(&client, user::Role::User).await;
+    let other_req = test::TestRequest::patch()
+        .uri(&format!("/users/{}", user_id))
+        .insert_header(("Authorization", format!("Bearer {}", other_token)))
+        .set_json(UpdateUser {
+            username: Some(format!("{}-other", prefix)),
+            ..Default::default()
+        })
+        .to_request();
+    let other_resp: actix_web::dev::ServiceResponse = test::call_service(&app, other_req).await;
+    assert_eq!(other_resp.status(), StatusCode::FORBIDDEN);
+
+    let req2 = test::TestRequest::patch()
+        .uri(&format!("/users/{}", user_id))
+        .insert_header(("Authorization", format!("Bearer {}", tokens.access_token)))
+        .set_json(UpdateUser {
+            username: Some(format!("{}-after", prefix)),
+            ..Default::default()
+        })
+        .to_request();
+
+    let resp2: actix_web::dev::ServiceResponse = test::call_service(&app, req2).await;
+    assert_eq!(resp2.status(), StatusCode::OK);
+
+    let updated: serde_json::Value = test::read_body_json(resp2).await;
+    assert_eq!(updated["username"], format!("{}-after", prefix));
+
+    let req3 = test::TestRequest::patch()
+        .uri(&format!("/users/{}", user_id))
+        .insert_header(("Authorization", format!("Bearer {}", tokens.access_token)))
+        .set_json(UpdateUser {
+            password: Some("new-password123".to_string()),
+            ..Default::default()
+        })
+        .to_request();
+
+    let resp3: actix_web::dev::ServiceResponse = test::call_service(&app, req3).await;
+    assert_eq!(resp3.status(), StatusCode::BAD_REQUEST);
+
+    let req4 = test::TestRequest::patch()
+        .uri(&format!("/users/{}", user_id))
+        .insert_header(("Authorization", format!("Bearer {}", tokens.access_token)))
+        .set_json(UpdateUser {
+            password: Some("new-password123".to_string()),
+            current_password: Some("password123".to_string()),
+            ..Default::default()
+        })
+        .to_request();
+
+    let resp4: actix_web::dev::ServiceResponse = test::call_service(&app, req4).await;
+    assert_eq!(resp4.status(), StatusCode::OK);
+
+    teardown(&client).await;
+}
+
+#[actix_rt::test]
+async fn test_token_subject_survives_email_change() {
+
+    let client: Client = setup().await;
+
+    let prefix: String = mongodb::bson::oid::ObjectId::new().to_hex();
+
+    let new_user: User = User {
+        id: None,
+        username: prefix.clone(),
+        email: format!("{}@example.com", prefix),
+        password: "password123".to_string(),
+        role: user::Role::User,
+        access_token: None,
+        refresh_token: None,
+        access_token_expires_at: None,
+        refresh_token_expires_at: None,
+        verified: false,
+    };
+
+    let (sent_mail, mailer) = common::memory_mailer();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(client.clone()))
+            .app_data(mailer.clone())
+            .service(web::resource("/register").route(web::post().to(register_user)))
+            .service(web::resource("/login").route(web::post().to(login_user)))
+            .service(web::resource("/auth/verify-email").route(web::post().to(verify_email)))
+            .service(web::resource("/auth/me").route(web::get().to(me)))
+            .service(web::resource("/users/{id}").route(web::patch().to(update_user)))
+    ).await;
+
+    let req1 = test::TestRequest::post()
+        .uri("/register")
+        .set_json(&new_user)
+        .to_request();
+    let inserted_id: serde_json::Value = test::call_and_read_body_json(&app, req1).await;
+    let user_id: &str = inserted_id["$oid"].as_str().unwrap();
+
+    let req2 = test::TestRequest::post()
+        .uri("/auth/verify-email")
+        .set_json(VerifyEmailRequest { token: common::token_from_last_email(&sent_mail, &new_user.email) })
+        .to_request();
+    let _: actix_web::dev::ServiceResponse = test::call_service(&app, req2).await;
+
+    let req3 = test::TestRequest::post()
+        .uri("/login")
+        .set_json(UserLogin { email: new_user.email.clone(), password: "password123".to_string() })
+        .to_request();
+    let tokens: AuthResponse = test::call_and_read_body_json(&app, req3).await;
+
+    assert_eq!(jwt::decode_access_token(&tokens.access_token).unwrap().sub, user_id);
+    assert_eq!(jwt::decode_refresh_token(&tokens.refresh_token).unwrap().sub, user_id);
+
+    let req4 = test::TestRequest::patch()
+        .uri(&format!("/users/{}", user_id))
+        .insert_header(("Authorization", format!("Bearer {}", tokens.access_token)))
+        .set_json(UpdateUser {
+            email: Some(format!("{}-after@example.com", prefix)),
+            current_password: Some("password123".to_string()),
+            ..Default::default()
+        })
+        .to_request();
+    let resp4: actix_web::dev::ServiceResponse = test::call_service(&app, req4).await;
+    assert_eq!(resp4.status(), StatusCode::OK);
+
+    // The token still names the same user after the email changed
+    let req5 = test::TestRequest::get()
+        .uri("/auth/me")
+        .insert_header(("Authorization", format!("Bearer {}", tokens.access_token)))
+        .to_request();
+    let resp5: actix_web::dev::ServiceResponse = test::call_service(&app, req5).await;
+    assert_eq!(resp5.status(), StatusCode::OK);
+
+    let profile: serde_json::Value = test::read_body_json(resp5).await;
+    assert_eq!(profile["id"], user_id);
+    assert_eq!(profile["email"], format!("{}-after@example.com", prefix));
+
+    teardown(&client).await;
+}
+
+#[actix_rt::test]
+async fn test_api_keys() {
+
+    let client: Client = setup().await;
+
+    let prefix: String = mongodb::bson::oid::ObjectId::new().to_hex();
+
+    let new_user: User = User {
+        id: None,
+        username: prefix.clone(),
+        email: format!("{}@example.com", prefix),
+        password: "password123".to_string(),
+        role: user::Role::User,
+        access_token: None,
+        refresh_token: None,
+        access_token_expires_at: None,
+        refresh_token_expires_at: None,
+        verified: false,
+    };
+
+    let (sent_mail, mailer) = common::memory_mailer();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(client.clone()))
+            .app_data(mailer.clone())
+            .service(web::resource("/register").route(web::post().to(register_user)))
+            .service(web::resource("/login").route(web::post().to(login_user)))
+            .service(web::resource("/auth/verify-email").route(web::post().to(verify_email)))
+            .service(web::resource("/auth/me").route(web::get().to(me)))
+            .service(web::resource("/auth/api-keys").route(web::get().to(list_api_keys)).route(web::post().to(create_api_key)))
+            .service(web::resource("/auth/api-keys/{id}").route(web::delete().to(revoke_api_key)))
+            .service(web::resource("/users/{id}").route(web::patch().to(update_user)))
+    ).await;
+
+    let req1 = test::TestRequest::post()
+        .uri("/register")
+        .set_json(&new_user)
+        .to_request();
+    let _: actix_web::dev::ServiceResponse = test::call_service(&app, req1).await;
+
+    let req2 = test::TestRequest::post()
+        .uri("/auth/verify-email")
+        .set_json(VerifyEmailRequest { token: common::token_from_last_email(&sent_mail, &new_user.email) })
+        .to_request();
+    let _: actix_web::dev::ServiceResponse = test::call_service(&app, req2).await;
+
+    let req3 = test::TestRequest::post()
+        .uri("/login")
+        .set_json(UserLogin { email: new_user.email.clone(), password: "password123".to_string() })
+        .to_request();
+    let tokens: AuthResponse = test::call_and_read_body_json(&app, req3).await;
+
+    let req4 = test::TestRequest::post()
+        .uri("/auth/api-keys")
+        .insert_header(("Authorization", format!("Bearer {}", tokens.access_token)))
+        .set_json(CreateApiKey { name: "deploy".to_string(), scopes: vec![Scope::LinksRead, Scope::LinksWrite], expires_in_days: Some(30) })
+        .to_request();
+    let resp4: actix_web::dev::ServiceResponse = test::call_service(&app, req4).await;
+    assert_eq!(resp4.status(), StatusCode::CREATED);
+
+    let created: serde_json::Value = test::read_body_json(resp4).await;
+    let key: String = created["key"].as_str().unwrap().to_string();
+    let key_id: String = created["id"].as_str().unwrap().to_string();
+    assert!(key.starts_with(created["prefix"].as_str().unwrap()));
+
+    let req5 = test::TestRequest::get()
+        .uri("/auth/me")
+        .insert_header(("X-API-Key", key.clone()))
+        .to_request();
+    let resp5: actix_web::dev::ServiceResponse = test::call_service(&app, req5).await;
+    assert_eq!(resp5.status(), StatusCode::OK);
+    let me_body: serde_json::Value = test::read_body_json(resp5).await;
+
+    let req6 = test::TestRequest::get()
+        .uri("/auth/me")
+        .insert_header(("Authorization", format!("ApiKey {}", key)))
+        .to_request();
+    let resp6: actix_web::dev::ServiceResponse = test::call_service(&app, req6).await;
+    assert_eq!(resp6.status(), StatusCode::OK);
+
+    // Keys cannot mint more keys
+    let req7 = test::TestRequest::post()
+        .uri("/auth/api-keys")
+        .insert_header(("X-API-Key", key.clone()))
+        .set_json(CreateApiKey { name: "copy".to_string(), scopes: vec![Scope::LinksRead], expires_in_days: None })
+        .to_request();
+    let resp7: actix_web::dev::ServiceResponse = test::call_service(&app, req7).await;
+    assert_eq!(resp7.status(), StatusCode::FORBIDDEN);
+
+    // Nor edit the owner's profile
+    let profile_req = test::TestRequest::patch()
+        .uri(&format!("/users/{}", me_body["id"].as_str().unwrap()))
+        .insert_header(("X-API-Key", key.clone()))
+        .set_json(UpdateUser { username: Some(format!("{}-renamed", prefix)), ..Default::default() })
+        .to_request();
+    let profile_resp: actix_web::dev::ServiceResponse = test::call_service(&app, profile_req).await;
+    assert_eq!(profile_resp.status(), StatusCode::FORBIDDEN);
+
+    // The key is never shown again, but its use is tracked
+    let req8 = test::TestRequest::get()
+        .uri("/auth/api-keys")
+        .insert_header(("Authorization", format!("Bearer {}", tokens.access_token)))
+        .to_request();
+    let listed: serde_json::Value = test::call_and_read_body_json(&app, req8).await;
+    assert_eq!(listed.as_array().unwrap().len(), 1);
+    assert!(listed[0].get("key").is_none());
+    assert!(listed[0]["last_used_at"].is_string());
+
+    let req9 = test::TestRequest::delete()
+        .uri(&format!("/auth/api-keys/{}", key_id))
+        .insert_header(("Authorization", format!("Bearer {}", tokens.access_token)))
+        .to_request();
+    let resp9: actix_web::dev::ServiceResponse = test::call_service(&app, req9).await;
+    assert_eq!(resp9.status(), StatusCode::NO_CONTENT);
+
+    let req10 = test::TestRequest::get()
+        .uri("/auth/me")
+        .insert_header(("X-API-Key", key))
+        .to_request();
+    let resp10: actix_web::dev::ServiceResponse = test::call_service(&app, req10).await;
+    assert_eq!(resp10.status(), StatusCode::UNAUTHORIZED);
+
+    teardown(&client).await;
+}
+
+#[actix_rt::test]
+async fn test_scopes() {
+
+    let client: Client = setup().await;
+
+    let (_, user_token) = common::signed_in_user(&client, user::Role::User).await;
+    let (_, auditor_token) = common::signed_in_user(&client, user::Role::Auditor).await;
+    let (_, admin_token) = common::signed_in_user(&client, user::Role::Admin).await;
+
+    let claims: jwt::Claims = jwt::decode_access_token(&auditor_token).unwrap();
+    assert_eq!(claims.scope.as_deref(), Some("links:read analytics:read users:read"));
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(client.clone()))
+            .service(web::resource("/auth/me").route(web::get().to(me)))
+            .service(web::resource("/auth/api-keys").route(web::post().to(create_api_key)))
+            .service(web::resource("/users").route(web::get().to(get_users)))
+    ).await;
+
+    let expected: [(&str, StatusCode); 3] = [
+        (&user_token, StatusCode::FORBIDDEN),
+        (&auditor_token, StatusCode::OK),
+        (&admin_token, StatusCode::OK),
+    ];
+    for (token, status) in expected {
+        let req = test::TestRequest::get()
+            .uri("/users")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        let resp: actix_web::dev::ServiceResponse = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), status);
+    }
+
+    // Keys cannot hold more than the role allows
+    let req1 = test::TestRequest::post()
+        .uri("/auth/api-keys")
+        .insert_header(("Authorization", format!("Bearer {}", user_token)))
+        .set_json(CreateApiKey { name: "too much".to_string(), scopes: vec![Scope::UsersAdmin], expires_in_days: None })
+        .to_request();
+    let resp1: actix_web::dev::ServiceResponse = test::call_service(&app, req1).await;
+    assert_eq!(resp1.status(), StatusCode::BAD_REQUEST);
+
+    // An admin key limited to reading links cannot list users
+    let req2 = test::TestRequest::post()
+        .uri("/auth/api-keys")
+        .insert_header(("Authorization", format!("Bearer {}", admin_token)))
+        .set_json(CreateApiKey { name: "read-only".to_string(), scopes: vec![Scope::LinksRead], expires_in_days: None })
+        .to_request();
+    let created: serde_json::Value = test::call_and_read_body_json(&app, req2).await;
+    let key: &str = created["key"].as_str().unwrap();
+
+    let req3 = test::TestRequest::get()
+        .uri("/users")
+        .insert_header(("X-API-Key", key))
+        .to_request();
+    let resp3: actix_web::dev::ServiceResponse = test::call_service(&app, req3).await;
+    assert_eq!(resp3.status(), StatusCode::FORBIDDEN);
+
+    let req4 = test::TestRequest::get()
+        .uri("/auth/me")
+        .insert_header(("X-API-Key", key))
+        .to_request();
+    let profile: serde_json::Value = test::call_and_read_body_json(&app, req4).await;
+    assert_eq!(profile["scopes"], serde_json::json!(["links:read"]));
+
+    teardown(&client).await;
+}
+
+#[actix_rt::test]
+async fn test_organizations() {
+
+    let client: Client = setup().await;
+
+    let (owner_id, owner_token) = common::signed_in_user(&client, user::Role::User).await;
+    let (member_id, member_token) = common::signed_in_user(&client, user::Role::User).await;
+
+    let (sent_mail, mailer) = common::memory_mailer();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(client.clone()))
+            .app_data(mailer.clone())
+            .service(web::resource("/auth/me").route(web::get().to(me)))
+            .service(web::resource("/orgs").route(web::get().to(list_orgs)).route(web::post().to(create_org)))
+            .service(web::resource("/orgs/active").route(web::put().to(set_active)))
+            .service(web::resource("/orgs/invitations/accept").route(web::post().to(accept_invitation)))
+            .service(web::resource("/orgs/{id}/members").route(web::get().to(list_members)))
+            .service(web::resource("/orgs/{id}/members/{user_id}").route(web::patch().to(update_member)).route(web::delete().to(remove_member)))
+            .service(web::resource("/orgs/{id}/invitations").route(web::post().to(invite_member)))
+            .service(web::resource("/auth/api-keys").route(web::post().to(create_api_key)))
+    ).await;
+
+    let req1 = test::TestRequest::post()
+        .uri("/orgs")
+        .insert_header(("Authorization", format!("Bearer {}", owner_token)))
+        .set_json(CreateOrganization { name: "Marketing".to_string() })
+        .to_request();
+    let created: serde_json::Value = test::call_and_read_body_json(&app, req1).await;
+    let org_id: String = created["id"].as_str().unwrap().to_string();
+    assert_eq!(created["role"], "owner");
+
+    // Outsiders cannot see the organization at all
+    let req2 = test::TestRequest::get()
+        .uri(&format!("/orgs/{}/members", org_id))
+        .insert_header(("Authorization", format!("Bearer {}", member_token)))
+        .to_request();
+    let resp2: actix_web::dev::ServiceResponse = test::call_service(&app, req2).await;
+    assert_eq!(resp2.status(), StatusCode::NOT_FOUND);
+
+    let req3 = test::TestRequest::get()
+        .uri("/auth/me")
+        .insert_header(("Authorization", format!("Bearer {}", member_token)))
+        .to_request();
+    let member: serde_json::Value = test::call_and_read_body_json(&app, req3).await;
+    let member_email: String = member["email"].as_str().unwrap().to_string();
+
+    let req4 = test::TestRequest::post()
+        .uri(&format!("/orgs/{}/invitations", org_id))
+        .insert_header(("Authorization", format!("Bearer {}", owner_token)))
+        .set_json(InviteMember { email: member_email.clone(), role: OrgRole::Editor })
+        .to_request();
+    let resp4: actix_web::dev::ServiceResponse = test::call_service(&app, req4).await;
+    assert_eq!(resp4.status(), StatusCode::ACCEPTED);
+
+    let token: String = common::token_from_email(&sent_mail, &member_email, "You have been invited to Marketing").await;
+
+    // API keys cannot manage members, whatever their scopes
+    let key_req = test::TestRequest::post()
+        .uri("/auth/api-keys")
+        .insert_header(("Authorization", format!("Bearer {}", owner_token)))
+        .set_json(CreateApiKey { name: "ci".to_string(), scopes: vec![Scope::LinksRead, Scope::LinksWrite], expires_in_days: None })
+        .to_request();
+    let created_key: serde_json::Value = test::call_and_read_body_json(&app, key_req).await;
+
+    let key_invite_req = test::TestRequest::post()
+        .uri(&format!("/orgs/{}/invitations", org_id))
+        .insert_header(("Authorization", format!("ApiKey {}", created_key["key"].as_str().unwrap())))
+        .set_json(InviteMember { email: "someone@example.com".to_string(), role: OrgRole::Viewer })
+        .to_request();
+    let key_invite_resp: actix_web::dev::ServiceResponse = test::call_service(&app, key_invite_req).await;
+    assert_eq!(key_invite_resp.status(), StatusCode::FORBIDDEN);
+
+    // Nor switch the organization every session works in
+    let key_active_req = test::TestRequest::put()
+        .uri("/orgs/active")
+        .insert_header(("Authorization", format!("ApiKey {}", created_key["key"].as_str().unwrap())))
+        .set_json(SetActiveOrg { org_id: org_id.clone() })
+        .to_request();
+    let key_active_resp: actix_web::dev::ServiceResponse = test::call_service(&app, key_active_req).await;
+    assert_eq!(key_active_resp.status(), StatusCode::FORBIDDEN);
+
+    // Only the invited address can accept
+    let req5 = test::TestRequest::post()
+        .uri("/orgs/invitations/accept")
+        .insert_header(("Authorization", format!("Bearer {}", owner_token)))
+        .set_json(AcceptInvitation { token: token.clone() })
+        .to_request();
+    let resp5: actix_web::dev::ServiceResponse = test::call_service(&app, req5).await;
+    assert_eq!(resp5.status(), StatusCode::BAD_REQUEST);
+
+    let req6 = test::TestRequest::post()
+        .uri("/orgs/invitations/accept")
+        .insert_header(("Authorization", format!("Bearer {}", member_token)))
+        .set_json(AcceptInvitation { token })
+        .to_request();
+    let resp6: actix_web::dev::ServiceResponse = test::call_service(&app, req6).await;
+    assert_eq!(resp6.status(), StatusCode::OK);
+
+    let req7 = test::TestRequest::get()
+        .uri(&format!("/orgs/{}/members", org_id))
+        .insert_header(("Authorization", format!("Bearer {}", member_token)))
+        .to_request();
+    let members: serde_json::Value = test::call_and_read_body_json(&app, req7).await;
+    assert_eq!(members.as_array().unwrap().len(), 2);
+
+    // Editors cannot manage members
+    let req8 = test::TestRequest::post()
+        .uri(&format!("/orgs/{}/invitations", org_id))
+        .insert_header(("Authorization", format!("Bearer {}", member_token)))
+        .set_json(InviteMember { email: "someone@example.com".to_string(), role: OrgRole::Viewer })
+        .to_request();
+    let resp8: actix_web::dev::ServiceResponse = test::call_service(&app, req8).await;
+    assert_eq!(resp8.status(), StatusCode::FORBIDDEN);
+
+    // The last owner cannot step down
+    let req9 = test::TestRequest::patch()
+        .uri(&format!("/orgs/{}/members/{}", org_id, owner_id.to_hex()))
+        .insert_header(("Authorization", format!("Bearer {}", owner_token)))
+        .set_json(UpdateMember { role: OrgRole::Admin })
+        .to_request();
+    let resp9: actix_web::dev::ServiceResponse = test::call_service(&app, req9).await;
+    assert_eq!(resp9.status(), StatusCode::CONFLICT);
+
+    let req10 = test::TestRequest::put()
+        .uri("/orgs/active")
+        .insert_header(("Authorization", format!("Bearer {}", member_token)))
+        .set_json(SetActiveOrg { org_id: org_id.clone() })
+        .to_request();
+    let active: serde_json::Value = test::call_and_read_body_json(&app, req10).await;
+    assert_eq!(active["scopes"], serde_json::json!(["links:read", "links:write", "analytics:read"]));
+
+    let req11 = test::TestRequest::get()
+        .uri("/orgs")
+        .insert_header(("Authorization", format!("Bearer {}", member_token)))
+        .to_request();
+    let orgs: serde_json::Value = test::call_and_read_body_json(&app, req11).await;
+    assert_eq!(orgs[0]["role"], "editor");
+    assert_eq!(orgs[0]["active"], true);
+
+    let req12 = test::TestRequest::delete()
+        .uri(&format!("/orgs/{}/members/{}", org_id, member_id.to_hex()))
+        .insert_header(("Authorization", format!("Bearer {}", owner_token)))
+        .to_request();
+    let resp12: actix_web::dev::ServiceResponse = test::call_service(&app, req12).await;
+    assert_eq!(resp12.status(), StatusCode::NO_CONTENT);
+
+    teardown(&client).await;
+}
+
+#[actix_rt::test]
+async fn test_oidc_login() {
+
+    let client: Client = setup().await;
+
+    let mock: common::MockOidc = common::start_mock_oidc("link-shortener").await;
+    let prefix: String = mongodb::bson::oid::ObjectId::new().to_hex();
+    {
+        let mut state = mock.state.lock().unwrap();
+        state.subject = format!("idp-{}", prefix);
+        state.email = format!("{}@example.com", prefix);
+        state.groups = vec!["engineering".to_string(), "link-admins".to_string()];
+    }
+
+    let provider: OidcProvider = OidcProvider::new(OidcConfig {
+        issuer: mock.issuer.clone(),
+        client_id: "link-shortener".to_string(),
+        client_secret: None,
+        redirect_uri: "http://localhost:8080/auth/oidc/callback".to_string(),
+        scopes: "openid email profile".to_string(),
+        groups_claim: "groups".to_string(),
+        admin_groups: vec!["link-admins".to_string()],
+        auditor_groups: vec!["auditors".to_string()],
+    });
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(client.clone()))
+            .app_data(web::Data::new(provider))
+            .service(web::resource("/auth/me").route(web::get().to(me)))
+            .service(web::resource("/auth/oidc/login").route(web::get().to(oidc_login)))
+            .service(web::resource("/auth/oidc/callback").route(web::get().to(oidc_callback)))
+    ).await;
+
+    let mut user_ids: Vec<serde_json::Value> = Vec::new();
+
+    // The first login creates the account, the second finds it again
+    for _ in 0..2 {
+        let req1 = test::TestRequest::get().uri("/auth/oidc/login").to_request();
+        let resp1: actix_web::dev::ServiceResponse = test::call_service(&app, req1).await;
+        assert_eq!(resp1.status(), StatusCode::FOUND);
+
+        let location: String = resp1.headers().get("Location").unwrap().to_str().unwrap().to_string();
+        assert!(location.starts_with(&format!("{}/authorize?", mock.issuer)));
+        let callback_query: String = mock.authorize(&location);
+        let state_cookie: Cookie<'static> = common::oidc_state_cookie(&resp1);
+
+        let req2 = test::TestRequest::get()
+            .uri(&format!("/auth/oidc/callback?{}", callback_query))
+            .cookie(state_cookie.clone())
+            .to_request();
+        let resp2: actix_web::dev::ServiceResponse = test::call_service(&app, req2).await;
+        assert_eq!(resp2.status(), StatusCode::OK);
+        let tokens: AuthResponse = test::read_body_json(resp2).await;
+
+        // State is single-use
+        let replay = test::TestRequest::get()
+            .uri(&format!("/auth/oidc/callback?{}", callback_query))
+            .cookie(state_cookie)
+            .to_request();
+        let replay_resp: actix_web::dev::ServiceResponse = test::call_service(&app, replay).await;
+        assert_eq!(replay_resp.status(), StatusCode::BAD_REQUEST);
+
+        let req3 = test::TestRequest::get()
+            .uri("/auth/me")
+            .insert_header(("Authorization", format!("Bearer {}", tokens.access_token)))
+            .to_request();
+        let profile: serde_json::Value = test::call_and_read_body_json(&app, req3).await;
+        assert_eq!(profile["email"], format!("{}@example.com", prefix));
+        assert_eq!(profile["role"], "Admin");
+        assert_eq!(profile["verified"], true);
+        user_ids.push(profile["id"].clone());
+    }
+
+    assert_eq!(user_ids[0], user_ids[1]);
+    // The provider's keys are downloaded once, not on every login
+    assert_eq!(mock.state.lock().unwrap().jwks_requests, 1);
+
+    // A callback the provider never issued is rejected
+    let req4 = test::TestRequest::get()
+        .uri("/auth/oidc/callback?code=forged&state=forged")
+        .to_request();
+    let resp4: actix_web::dev::ServiceResponse = test::call_service(&app, req4).await;
+    assert_eq!(resp4.status(), StatusCode::BAD_REQUEST);
+
+    // So is a genuine callback opened in a browser that did not start the login
+    let csrf_login_req = test::TestRequest::get().uri("/auth/oidc/login").to_request();
+    let csrf_login_resp: actix_web::dev::ServiceResponse = test::call_service(&app, csrf_login_req).await;
+    let callback_query: String = mock.authorize(csrf_login_resp.headers().get("Location").unwrap().to_str().unwrap());
+
+    let other_login_req = test::TestRequest::get().uri("/auth/oidc/login").to_request();
+    let other_login_resp: actix_web::dev::ServiceResponse = test::call_service(&app, other_login_req).await;
+
+    let csrf_req = test::TestRequest::get()
+        .uri(&format!("/auth/oidc/callback?{}", callback_query))
+        .to_request();
+    let csrf_resp: actix_web::dev::ServiceResponse = test::call_service(&app, csrf_req).await;
+    assert_eq!(csrf_resp.status(), StatusCode::BAD_REQUEST);
+
+    let csrf_req = test::TestRequest::get()
+        .uri(&format!("/auth/oidc/callback?{}", callback_query))
+        .cookie(common::oidc_state_cookie(&other_login_resp))
+        .to_request();
+    let csrf_resp: actix_web::dev::ServiceResponse = test::call_service(&app, csrf_req).await;
+    assert_eq!(csrf_resp.status(), StatusCode::BAD_REQUEST);
+
+    // Accounts with two-factor authentication still need their code
+    let enrollments: Collection<TotpEnrollment> = client.database("shortener_link").collection("totp");
+    enrollments.insert_one(TotpEnrollment {
+        id: None,
+        user_id: mongodb::bson::oid::ObjectId::parse_str(user_ids[0].as_str().unwrap()).unwrap(),
+        secret: totp_rs::Secret::Raw(vec![7; 20]).to_encoded().to_string(),
+        enabled: true,
+        recovery_code_hashes: Vec::new(),
+        last_time_step: None,
+    }).await.unwrap();
+
+    let mfa_login_req = test::TestRequest::get().uri("/auth/oidc/login").to_request();
+    let mfa_login_resp: actix_web::dev::ServiceResponse = test::call_service(&app, mfa_login_req).await;
+    let callback_query: String = mock.authorize(mfa_login_resp.headers().get("Location").unwrap().to_str().unwrap());
+
+    let mfa_callback_req = test::TestRequest::get()
+        .uri(&format!("/auth/oidc/callback?{}", callback_query))
+        .cookie(common::oidc_state_cookie(&mfa_login_resp))
+        .to_request();
+    let challenge: serde_json::Value = test::call_and_read_body_json(&app, mfa_callback_req).await;
+    assert_eq!(challenge["mfa_required"], true);
+    assert!(challenge.get("access_token").is_none());
+
+    // An unverified account with the same email, possibly registered by someone else, is not taken over
+    let squatted_email: String = format!("{}-squatted@example.com", prefix);
+    let users: Collection<User> = client.database("shortener_link").collection("users");
+    users.insert_one(User {
+        id: None,
+        username: format!("{}-squatter", prefix),
+        email: squatted_email.clone(),
+        password: bcrypt::hash("attacker-password", 4).unwrap(),
+        role: user::Role::User,
+        access_token: None,
+        refresh_token: None,
+        access_token_expires_at: None,
+        refresh_token_expires_at: None,
+        verified: false,
+    }).await.unwrap();
+    {
+        let mut state = mock.state.lock().unwrap();
+        state.subject = format!("idp-{}-squatted", prefix);
+        state.email = squatted_email.clone();
+    }
+
+    let req5 = test::TestRequest::get().uri("/auth/oidc/login").to_request();
+    let resp5: actix_web::dev::ServiceResponse = test::call_service(&app, req5).await;
+    let callback_query: String = mock.authorize(resp5.headers().get("Location").unwrap().to_str().unwrap());
+
+    let req6 = test::TestRequest::get()
+        .uri(&format!("/auth/oidc/callback?{}", callback_query))
+        .cookie(common::oidc_state_cookie(&resp5))
+        .to_request();
+    let resp6: actix_web::dev::ServiceResponse = test::call_service(&app, req6).await;
+    assert_eq!(resp6.status(), StatusCode::CONFLICT);
+
+    let squatter: User = users.find_one(doc! { "email": &squatted_email }).await.unwrap().unwrap();
+    assert!(!squatter.verified);
+
+    teardown(&client).await;
+}
+
+#[actix_rt::test]
+async fn test_verify_email() {
+
+    let client: Client = setup().await;
+
+    let prefix: String = mongodb::bson::oid::ObjectId::new().to_hex();
+
+    let new_user: User = User {
+        id: None,
+        username: prefix.clone(),
+        email: format!("{}@example.com", prefix),
+        password: "password123".to_string(),
+        role: user::Role::User,
+        access_token: None,
+        refresh_token: None,
+        access_token_expires_at: None,
+        refresh_token_expires_at: None,
+        verified: true,
+    };
+
+    let user: UserLogin = UserLogin {
+        email: new_user.email.clone(),
+        password: "password123".to_string(),
+    };
+
+    let (sent_mail, mailer) = common::memory_mailer();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(client.clone()))
+            .app_data(mailer.clone())
+            .service(web::resource("/register").route(web::post().to(register_user)))
+            .service(web::resource("/login").route(web::post().to(login_user)))
+            .service(web::resource("/auth/verify-email").route(web::post().to(verify_email)))
+    ).await;
+
+    let req1 = test::TestRequest::post()
+        .uri("/register")
+        .set_json(&new_user)
+        .to_request();
+    let _: actix_web::dev::ServiceResponse = test::call_service(&app, req1).await;
+
+    // The client cannot mark itself as verified
+    let req2 = test::TestRequest::post()
+        .uri("/login")
+        .set_json(&user)
+        .to_request();
+    let resp2: actix_web::dev::ServiceResponse = test::call_service(&app, req2).await;
+    assert_eq!(resp2.status(), StatusCode::FORBIDDEN);
+
+    let req3 = test::TestRequest::post()
+        .uri("/auth/verify-email")
+        .set_json(VerifyEmailRequest { token: "not-a-token".to_string() })
+        .to_request();
+    let resp3: actix_web::dev::ServiceResponse = test::call_service(&app, req3).await;
+    assert_eq!(resp3.status(), StatusCode::BAD_REQUEST);
+
+    let req4 = test::TestRequest::post()
+        .uri("/auth/verify-email")
+        .set_json(VerifyEmailRequest { token: common::token_from_last_email(&sent_mail, &new_user.email) })
+        .to_request();
+    let resp4: actix_web::dev::ServiceResponse = test::call_service(&app, req4).await;
+    assert_eq!(resp4.status(), StatusCode::OK);
+
+    let req5 = test::TestRequest::post()
+        .uri("/login")
+        .set_json(&user)
+        .to_request();
+    let resp5: actix_web::dev::ServiceResponse = test::call_service(&app, req5).await;
+    assert_eq!(resp5.status(), StatusCode::OK);
+
+    teardown(&client).await;
+}
+
+#[actix_rt::test]
+async fn test_password_reset() {
+
+    let client: Client = setup().await;
+
+    let prefix: String = mongodb::bson::oid::ObjectId::new().to_hex();
+
+    let new_user: User = User {
+        id: None,
+        username: prefix.clone(),
+        email: format!("{}@example.com", prefix),
+        password: "password123".to_string(),
+        role: user::Role::User,
+        access_token: None,
+        refresh_token: None,
+        access_token_expires_at: None,
+        refresh_token_expires_at: None,
+        verified: false,
+    };
+
+    let (sent_mail, mailer) = common::memory_mailer();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(client.clone()))
+            .app_data(mailer.clone())
+            .service(web::resource("/register").route(web::post().to(register_user)))
+            .service(web::resource("/login").route(web::post().to(login_user)))
+            .service(web::resource("/auth/verify-email").route(web::post().to(verify_email)))
+            .service(web::resource("/auth/forgot-password").route(web::post().to(forgot_password)))
+            .service(web::resource("/auth/reset-password").route(web::post().to(reset_password)))
+            .service(web::resource("/auth/me").route(web::get().to(me)))
+    ).await;
+
+    let req1 = test::TestRequest::post()
+        .uri("/register")
+        .set_json(&new_user)
+        .to_request();
+    let _: actix_web::dev::ServiceResponse = test::call_service(&app, req1).await;
+
+    let req2 = test::TestRequest::post()
+        .uri("/auth/verify-email")
+        .set_json(VerifyEmailRequest { token: common::token_from_last_email(&sent_mail, &new_user.email) })
+        .to_request();
+    let _: actix_web::dev::ServiceResponse = test::call_service(&app, req2).await;
+
+    let login_req = test::TestRequest::post()
+        .uri("/login")
+        .set_json(UserLogin { email: new_user.email.clone(), password: "password123".to_string() })
+        .to_request();
+    let old_tokens: AuthResponse = test::call_and_read_body_json(&app, login_req).await;
+
+    // Unknown and known emails get the same answer
+    let req3 = test::TestRequest::post()
+        .uri("/auth/forgot-password")
+        .set_json(ForgotPasswordRequest { email: format!("{}-unknown@example.com", prefix) })
+        .to_request();
+    let resp3: actix_web::dev::ServiceResponse = test::call_service(&app, req3).await;
+    assert_eq!(resp3.status(), StatusCode::ACCEPTED);
+    let body3: web::Bytes = test::read_body(resp3).await;
+
+    let req4 = test::TestRequest::post()
+        .uri("/auth/forgot-password")
+        .set_json(ForgotPasswordRequest { email: new_user.email.clone() })
+        .to_request();
+    let resp4: actix_web::dev::ServiceResponse = test::call_service(&app, req4).await;
+    assert_eq!(resp4.status(), StatusCode::ACCEPTED);
+    assert_eq!(test::read_body(resp4).await, body3);
+
+    let token: String = common::token_from_email(&sent_mail, &new_user.email, "Reset your password").await;
+
+    let req5 = test::TestRequest::post()
+        .uri("/auth/reset-password")
+        .set_json(ResetPasswordRequest { token: token.clone(), new_password: "new-password123".to_string() })
+        .to_request();
+    let resp5: actix_web::dev::ServiceResponse = test::call_service(&app, req5).await;
+    assert_eq!(resp5.status(), StatusCode::OK);
+
+    // Sessions from before the reset are signed out
+    let me_req = test::TestRequest::get()
+        .uri("/auth/me")
+        .insert_header(("Authorization", format!("Bearer {}", old_tokens.access_token)))
+        .to_request();
+    let me_resp: actix_web::dev::ServiceResponse = test::call_service(&app, me_req).await;
+    assert_eq!(me_resp.status(), StatusCode::UNAUTHORIZED);
+
+    // The token only works once
+    let req6 = test::TestRequest::post()
+        .uri("/auth/reset-password")
+        .set_json(ResetPasswordRequest { token, new_password: "another-password123".to_string() })
+        .to_request();
+    let resp6: actix_web::dev::ServiceResponse = test::call_service(&app, req6).await;
+    assert_eq!(resp6.status(), StatusCode::BAD_REQUEST);
+
+    let req7 = test::TestRequest::post()
+        .uri("/login")
+        .set_json(UserLogin { email: new_user.email.clone(), password: "new-password123".to_string() })
+        .to_request();
+    let resp7: actix_web::dev::ServiceResponse = test::call_service(&app, req7).await;
+    assert_eq!(resp7.status(), StatusCode::OK);
+
+    teardown(&client).await;
+}
+
+#[actix_rt::test]
+async fn test_totp_login() {
+
+    let client: Client = setup().await;
+
+    let prefix: String = mongodb::bson::oid::ObjectId::new().to_hex();
+
+    let new_user: User = User {
+        id: None,
+        username: prefix.clone(),
+        email: format!("{}@example.com", prefix),
+        password: "password123".to_string(),
+        role: user::Role::Admin,
+        access_token: None,
+        refresh_token: None,
+        access_token_expires_at: None,
+        refresh_token_expires_at: None,
+        verified: false,
+    };
+
+    let user: UserLogin = UserLogin {
+        email: new_user.email.clone(),
+        password: "password123".to_string(),
+    };
+
+    let (sent_mail, mailer) = common::memory_mailer();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(client.clone()))
+            .app_data(mailer.clone())
+            .service(web::resource("/register").route(web::post().to(register_user)))
+            .service(web::resource("/login").route(web::post().to(login_user)))
+            .service(web::resource("/auth/verify-email").route(web::post().to(verify_email)))
+            .service(web::resource("/auth/totp/setup").route(web::post().to(setup_totp)))
+            .service(web::resource("/auth/totp/confirm").route(web::post().to(confirm_totp)))
+            .service(web::resource("/auth/totp/verify").route(web::post().to(verify_totp)))
+    ).await;
+
+    let req1 = test::TestRequest::post()
+        .uri("/register")
+        .set_json(&new_user)
+        .to_request();
+    let _: actix_web::dev::ServiceResponse = test::call_service(&app, req1).await;
+
+    let req2 = test::TestRequest::post()
+        .uri("/auth/verify-email")
+        .set_json(VerifyEmailRequest { token: common::token_from_last_email(&sent_mail, &new_user.email) })
+        .to_request();
+    let _: actix_web::dev::ServiceResponse = test::call_service(&app, req2).await;
+
+    let req3 = test::TestRequest::post()
+        .uri("/login")
+        .set_json(&user)
+        .to_request();
+    let tokens: AuthResponse = test::call_and_read_body_json(&app, req3).await;
+
+    let req4 = test::TestRequest::post()
+        .uri("/auth/totp/setup")
+        .insert_header(("Authorization", format!("Bearer {}", tokens.access_token)))
+        .to_request();
+    let enrollment: serde_json::Value = test::call_and_read_body_json(&app, req4).await;
+    assert!(enrollment["otpauth_uri"].as_str().unwrap().starts_with("otpauth://totp/"));
+
+    let secret: Vec<u8> = totp_rs::Secret::Encoded(enrollment["secret"].as_str().unwrap().to_string()).to_bytes().unwrap();
+    let code: String = totp_rs::TOTP::new(totp_rs::Algorithm::SHA1, 6, 1, 30, secret, None, "test".to_string())
+        .unwrap()
+        .generate_current()
+        .unwrap();
+
+    let req5 = test::TestRequest::post()
+        .uri("/auth/totp/confirm")
+        .insert_header(("Authorization", format!("Bearer {}", tokens.access_token)))
+        .set_json(TotpCodeRequest { code: code.clone() })
+        .to_request();
+    let confirmation: serde_json::Value = test::call_and_read_body_json(&app, req5).await;
+    let recovery_code: String = confirmation["recovery_codes"][0].as_str().unwrap().to_string();
+
+    // The password alone is no longer enough
+    let req6 = test::TestRequest::post()
+        .uri("/login")
+        .set_json(&user)
+        .to_request();
+    let challenge: serde_json::Value = test::call_and_read_body_json(&app, req6).await;
+    assert_eq!(challenge["mfa_required"], true);
+    assert!(challenge.get("access_token").is_none());
+    let mfa_token: String = challenge["mfa_token"].as_str().unwrap().to_string();
+
+    let req7 = test::TestRequest::post()
+        .uri("/auth/totp/verify")
+        .set_json(TotpVerifyRequest { mfa_token: mfa_token.clone(), code: None, recovery_code: Some(recovery_code.clone()) })
+        .to_request();
+    let resp7: actix_web::dev::ServiceResponse = test::call_service(&app, req7).await;
+    assert_eq!(resp7.status(), StatusCode::OK);
+
+    // MFA tokens are single-use
+    let req8 = test::TestRequest::post()
+        .uri("/auth/totp/verify")
+        .set_json(TotpVerifyRequest {
+            mfa_token,
+            code: None,
+            recovery_code: Some(confirmation["recovery_codes"][1].as_str().unwrap().to_string()),
+        })
+        .to_request();
+    let resp8: actix_web::dev::ServiceResponse = test::call_service(&app, req8).await;
+    assert_eq!(resp8.status(), StatusCode::UNAUTHORIZED);
+
+    let req9 = test::TestRequest::post()
+        .uri("/login")
+        .set_json(&user)
+        .to_request();
+    let challenge: serde_json::Value = test::call_and_read_body_json(&app, req9).await;
+    let mfa_token: String = challenge["mfa_token"].as_str().unwrap().to_string();
+
+    // Recovery codes are single-use
+    let req10 = test::TestRequest::post()
+        .uri("/auth/totp/verify")
+        .set_json(TotpVerifyRequest { mfa_token: mfa_token.clone(), code: None, recovery_code: Some(recovery_code) })
+        .to_request();
+    let resp10: actix_web::dev::ServiceResponse = test::call_service(&app, req10).await;
+    assert_eq!(resp10.status(), StatusCode::UNAUTHORIZED);
+
+    // So are codes: the one that confirmed the setup cannot sign in
+    let req11 = test::TestRequest::post()
+        .uri("/auth/totp/verify")
+        .set_json(TotpVerifyRequest { mfa_token: mfa_token.clone(), code: Some(code), recovery_code: None })
+        .to_request();
+    let resp11: actix_web::dev::ServiceResponse = test::call_service(&app, req11).await;
+    assert_eq!(resp11.status(), StatusCode::UNAUTHORIZED);
+
+    // A third wrong code retires the token, even for a valid recovery code
+    let req12 = test::TestRequest::post()
+        .uri("/auth/totp/verify")
+        .set_json(TotpVerifyRequest { mfa_token: mfa_token.clone(), code: Some("000000".to_string()), recovery_code: None })
+        .to_request();
+    let resp12: actix_web::dev::ServiceResponse = test::call_service(&app, req12).await;
+    assert_eq!(resp12.status(), StatusCode::UNAUTHORIZED);
+
+    let unused_recovery_code: String = confirmation["recovery_codes"][2].as_str().unwrap().to_string();
+    let req13 = test::TestRequest::post()
+        .uri("/auth/totp/verify")
+        .set_json(TotpVerifyRequest { mfa_token, code: None, recovery_code: Some(unused_recovery_code.clone()) })
+        .to_request();
+    let resp13: actix_web::dev::ServiceResponse = test::call_service(&app, req13).await;
+    assert_eq!(resp13.status(), StatusCode::UNAUTHORIZED);
+
+    let req14 = test::TestRequest::post()
+        .uri("/login")
+        .set_json(&user)
+        .to_request();
+    let challenge: serde_json::Value = test::call_and_read_body_json(&app, req14).await;
+
+    let req15 = test::TestRequest::post()
+        .uri("/auth/totp/verify")
+        .set_json(TotpVerifyRequest {
+            mfa_token: challenge["mfa_token"].as_str().unwrap().to_string(),
+            code: None,
+            recovery_code: Some(unused_recovery_code),
+        })
+        .to_request();
+    let resp15: actix_web::dev::ServiceResponse = test::call_service(&app, req15).await;
+    assert_eq!(resp15.status(), StatusCode::OK);
+
+    teardown(&client).await;
+}
+
+#[actix_rt::test]
+async fn test_login_lockout() {
+
+    let client: Client = setup().await;
+
+    let prefix: String = mongodb::bson::oid::ObjectId::new().to_hex();
+
+    let new_user: User = User {
+        id: None,
+        username: prefix.clone(),
+        email: format!("{}@example.com", prefix),
+        password: "password123".to_string(),
+        role: user::Role::User,
+        access_token: None,
+        refresh_token: None,
+        access_token_expires_at: None,
+        refresh_token_expires_at: None,
+        verified: false,
+    };
+
+    let (_, mailer) = common::memory_mailer();
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(client.clone()))
+            .app_data(mailer.clone())
+            .service(web::resource("/register").route(web::post().to(register_user)))
+            .service(web::resource("/login").route(web::post().to(login_user)))
+    ).await;
+
+    let req1 = test::TestRequest::post()
+        .uri("/register")
+        .set_json(&new_user)
+        .to_request();
+    let _: actix_web::dev::ServiceResponse = test::call_service(&app, req1).await;
+
+    // Unknown emails and wrong passwords are indistinguishable
+    let req2 = test::TestRequest::post()
+        .uri("/login")
+        .set_json(UserLogin { email: format!("{}-unknown@example.com", prefix), password: "password123".to_string() })
+        .to_request();
+    let resp2: actix_web::dev::ServiceResponse = test::call_service(&app, req2).await;
+    assert_eq!(resp2.status(), StatusCode::UNAUTHORIZED);
+    let body2: web::Bytes = test::read_body(resp2).await;
+
+    for _ in 0..6 {
+        let req3 = test::TestRequest::post()
+            .uri("/login")
+            .set_json(UserLogin { email: new_user.email.clone(), password: "wrong-password".to_string() })
+            .to_request();
+        let resp3: actix_web::dev::ServiceResponse = test::call_service(&app, req3).await;
+        assert_eq!(resp3.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(test::read_body(resp3).await, body2);
+    }
+
+    // Locked out even with the right password
+    let req4 = test::TestRequest::post()
+        .uri("/login")
+        .set_json(UserLogin { email: new_user.email.clone(), password: "password123".to_string() })
+        .to_request();
+    let resp4: actix_web::dev::ServiceResponse = test::call_service(&app, req4).await;
+    assert_eq!(resp4.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(resp4.headers().get("Retry-After").is_some());
+
+    teardown(&client).await;
+}
+
+#[actix_rt::test]
+async fn test_jwt_key_rotation() {
+
+    let claims: serde_json::Value = serde_json::json!({
+        "sub": "rotation-test",
+        "exp": chrono::Utc::now().timestamp() + 600,
+    });
+
+    let old_keys: JwtKeys = JwtKeys::from_pem(
+        "2025-rsa",
+        Algorithm::RS256,
+        include_bytes!("fixtures/rsa_private.pem"),
+        include_bytes!("fixtures/rsa_public.pem"),
+    ).unwrap();
+    let old_token: String = old_keys.sign(&claims).unwrap();
+
+    let mut new_keys: JwtKeys = JwtKeys::from_pem(
+        "2026-ed25519",
+        Algorithm::EdDSA,
+        include_bytes!("fixtures/ed25519_private.pem"),
+        include_bytes!("fixtures/ed25519_public.pem"),
+    ).unwrap();
+    new_keys.add_verification_key("2026-ec", include_bytes!("fixtures/ec_public.pem")).unwrap();
+
+    // Not accepted until the old public key is added back for the rotation
+    assert!(new_keys.verify::<serde_json::Value>(&old_token, Validation::new(Algorithm::EdDSA)).is_err());
+    new_keys.add_verification_key("2025-rsa", include_bytes!("fixtures/rsa_public.pem")).unwrap();
+
+    let decoded: serde_json::Value = new_keys.verify(&old_token, Validation::new(Algorithm::EdDSA)).unwrap();
+    assert_eq!(decoded["sub"], "rotation-test");
+
+    let new_token: String = new_keys.sign(&claims).unwrap();
+    assert_eq!(jsonwebtoken::decode_header(&new_token).unwrap().kid.as_deref(), Some("2026-ed25519"));
+    assert!(new_keys.verify::<serde_json::Value>(&new_token, Validation::new(Algorithm::EdDSA)).is_ok());
+
+    let jwks: serde_json::Value = new_keys.jwks();
+    let kids: Vec<&str> = jwks["keys"].as_array().unwrap().iter().map(|key| key["kid"].as_str().unwrap()).collect();
+    assert_eq!(kids, vec!["2026-ed25519", "2026-ec", "2025-rsa"]);
+    assert_eq!(jwks["keys"][0]["crv"], "Ed25519");
+    assert_eq!(jwks["keys"][1]["kty"], "EC");
+    assert_eq!(jwks["keys"][2]["e"], "AQAB");
+
+    // Shared secrets are never published
+    assert_eq!(JwtKeys::hmac("default", b"secret").jwks()["keys"].as_array().unwrap().len(), 0);
+
+    // Moving back to a shared secret can still accept the old public key
+    let mut hmac_keys: JwtKeys = JwtKeys::hmac("default", b"secret");
+    hmac_keys.add_verification_key("2025-rsa", include_bytes!("fixtures/rsa_public.pem")).unwrap();
+    let decoded: serde_json::Value = hmac_keys.verify(&old_token, Validation::new(Algorithm::HS256)).unwrap();
+    assert_eq!(decoded["sub"], "rotation-test");
+    assert_eq!(hmac_keys.jwks()["keys"][0]["kid"], "2025-rsa");
+}
+
+#[actix_rt::test]
+async fn test_token_types_are_not_interchangeable() {
+
+    let access_token: String = jwt::generate_jwt("user-id", "Test User", &user::Role::User).unwrap();
+    let refresh_token: String = jwt::generate_refresh_token("user-id", "Test User", &user::Role::User).unwrap();
+    let service_token: String = jwt::generate_service_token("analytics", 60).unwrap();
+
+    let claims: jwt::Claims = jwt::decode_access_token(&access_token).unwrap();
+    assert_eq!(claims.typ, jwt::TokenType::Access);
+    assert_eq!(claims.sub, "user-id");
+    assert!(claims.iat <= claims.nbf && claims.nbf < claims.exp);
+    assert!(!claims.jti.is_empty());
+    assert_ne!(claims.jti, jwt::decode_access_token(&jwt::generate_jwt("user-id", "Test User", &user::Role::User).unwrap()).unwrap().jti);
+
+    assert!(jwt::decode_access_token(&refresh_token).is_err());
+    assert!(jwt::decode_access_token(&service_token).is_err());
+    assert!(jwt::decode_refresh_token(&access_token).is_err());
+    assert_eq!(jwt::decode_refresh_token(&refresh_token).unwrap().typ, jwt::TokenType::Refresh);
+    assert_eq!(jwt::decode_service_token(&service_token).unwrap().sub, "analytics");
+}